@@ -0,0 +1,177 @@
+/// UART baud rate selection
+///
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+/// Baud rates tried by auto negotiation, fastest first
+///
+/// Original uploader supports 9600, 19200, 57600 and 115200, faster rates
+/// depend on USB-UART adapter
+pub const BAUD_RATES: &[u32] = &[460800, 230400, 115200, 57600, 19200, 9600];
+
+/// Boot clock of the board, internal HSI oscillator
+pub const HSI_CLOCK: u32 = 8_000_000;
+
+/// Commonly used baud rates, anything else is most likely a typo
+const STANDARD_RATES: &[u32] = &[
+    1200, 2400, 4800, 9600, 14400, 19200, 38400, 57600,
+    115200, 128000, 230400, 256000, 460800, 921600,
+];
+
+/// Rate error UART still works with, percent
+pub const MAX_ERROR: f64 = 3.0;
+
+/// Rate error worth warning about, percent
+const WARN_ERROR: f64 = 1.5;
+
+/// UART baud rate divider
+///
+/// Board UART divides clock by 16 * (integer + fraction / 64)
+pub struct Divider {
+    pub integer: u32,
+    pub fraction: u32,
+    /// Baud rate really generated with the divider
+    pub actual: u32,
+    /// Difference between real and requested baud rate, percent
+    pub error: f64,
+}
+
+/// Calculate divider board UART uses for the baud rate
+///
+/// None when the rate is out of divider range
+pub fn divider(clock: u32, baud_rate: u32) -> Option<Divider> {
+    if baud_rate == 0 {
+        return None;
+    }
+
+    // divider in 1/64 units rounded to nearest
+    let div = (clock as u64 * 4 + baud_rate as u64 / 2) / baud_rate as u64;
+    let integer = (div / 64) as u32;
+    if integer == 0 || integer > 0xFFFF {
+        return None;
+    }
+
+    let actual = (clock as u64 * 4 / div) as u32;
+    return Some(Divider {
+        integer,
+        fraction: (div % 64) as u32,
+        actual,
+        error: (actual as f64 - baud_rate as f64) * 100.0 / baud_rate as f64,
+    });
+}
+
+/// Check if the board is able to work on the baud rate
+///
+/// Returns warning message for rates which will probably work but look
+/// suspicious, and error for rates the board can not generate
+pub fn check(clock: u32, baud_rate: u32) -> Result<Option<String>, String> {
+    let div = match divider(clock, baud_rate) {
+        Some(div) => div,
+        None => return Err(format!("Baud rate {} can not be generated from {} Hz clock", baud_rate, clock)),
+    };
+
+    return check_divider(clock, baud_rate, &div);
+}
+
+/// Check rate error of the divider and look for typos in the baud rate
+///
+/// With 1/64 fraction divider error is below 0.8% for any rate
+/// `divider` accepts, limits are there for rougher dividers
+fn check_divider(clock: u32, baud_rate: u32, div: &Divider) -> Result<Option<String>, String> {
+    debug!("Baud rate {} divider {}+{}/64 actual {} error {:.2}%",
+        baud_rate, div.integer, div.fraction, div.actual, div.error);
+
+    if div.error.abs() > MAX_ERROR {
+        return Err(format!("Baud rate {} error is {:.1}% ({} real) at {} Hz clock",
+            baud_rate, div.error, div.actual, clock));
+    }
+
+    if div.error.abs() > WARN_ERROR {
+        return Ok(Some(format!("Baud rate {} error is {:.1}%, connection could be unstable", baud_rate, div.error)));
+    }
+
+    if !STANDARD_RATES.contains(&baud_rate) {
+        // lost digit is the most common typo
+        let nearest = STANDARD_RATES.iter()
+            .min_by_key(|&&rate| (rate as i64 - baud_rate as i64).abs().min(
+                (rate as i64 - baud_rate as i64 * 10).abs()))
+            .unwrap();
+        return Ok(Some(format!("Baud rate {} is not a standard one, did you mean {}?", baud_rate, nearest)));
+    }
+
+    return Ok(None);
+}
+
+/// Baud rate requested from command line
+#[derive(Clone, Copy)]
+pub enum BaudRate {
+    /// Find the fastest stable baud rate
+    Auto,
+    Fixed(u32),
+}
+
+impl FromStr for BaudRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BaudRate, String> {
+        if s == "auto" {
+            return Ok(BaudRate::Auto);
+        }
+
+        return match s.parse::<u32>() {
+            Ok(0) | Err(_) => Err(format!("Wrong baud rate {}, number or \"auto\" expected", s)),
+            Ok(rate) => Ok(BaudRate::Fixed(rate)),
+        };
+    }
+}
+
+impl fmt::Display for BaudRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BaudRate::Auto => write!(f, "auto"),
+            BaudRate::Fixed(rate) => write!(f, "{}", rate),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divider_for_115200() {
+        let div = divider(HSI_CLOCK, 115200).unwrap();
+        // 8 MHz / 16 / 115200 = 4.34
+        assert_eq!((div.integer, div.fraction), (4, 22));
+        assert_eq!(div.actual, 115107);
+        assert!(div.error < 0.0 && div.error > -0.1);
+
+        assert_eq!(check(HSI_CLOCK, 115200), Ok(None));
+    }
+
+    #[test]
+    fn lost_digit_warning() {
+        let warning = check(HSI_CLOCK, 11520).unwrap().unwrap();
+        assert!(warning.contains("did you mean 115200"), "{}", warning);
+    }
+
+    #[test]
+    fn rate_too_high_for_clock() {
+        // integer part of divider would be 0
+        assert!(divider(HSI_CLOCK, 921600).is_none());
+        assert!(check(HSI_CLOCK, 921600).is_err());
+        assert!(divider(HSI_CLOCK, 500000).is_some());
+        assert!(divider(HSI_CLOCK, 0).is_none());
+    }
+
+    #[test]
+    fn rate_error_limits() {
+        let rough = |error| Divider { integer: 1, fraction: 0, actual: 0, error };
+
+        assert!(check_divider(HSI_CLOCK, 115200, &rough(-3.5)).is_err());
+        assert!(check_divider(HSI_CLOCK, 115200, &rough(2.0)).unwrap().is_some());
+        assert_eq!(check_divider(HSI_CLOCK, 115200, &rough(0.5)), Ok(None));
+    }
+}
@@ -1,7 +1,10 @@
 use std::{
     fmt,
-    io::{ self, Write },
+    io::{ self, Read, Write },
     boxed::Box,
+    collections::VecDeque,
+    sync::{ Arc, Condvar, Mutex },
+    time::{ Duration, Instant },
 };
 
 use serialport::{
@@ -11,12 +14,16 @@ use serialport::{
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
+    /// Not enough bytes arrived before read timeout expired
+    Timeout { expected: usize, received: usize },
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Io(ref err) => write!(f, "[{}]", err),
+            Error::Timeout { expected, received } =>
+                write!(f, "Read timeout, received {} of {} bytes", received, expected),
         }
     }
 }
@@ -28,23 +35,218 @@ impl From<io::Error> for Error {
     }
 }
 
-pub type ComPort = Box<dyn SerialPort>;
+impl From<serialport::Error> for Error {
+    fn from(err: serialport::Error) -> Error {
+        Error::Io(err.into())
+    }
+}
+
+/// Byte stream connected to the board
+///
+/// Anything readable and writable with controllable read timeout and
+/// line speed can be used to talk to the board
+///
+pub trait Transport: Read + Write {
+    fn timeout(&self) -> Duration;
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error>;
+    fn baud_rate(&self) -> Result<u32, Error>;
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), Error>;
+}
+
+impl Transport for dyn SerialPort {
+    fn timeout(&self) -> Duration {
+        return SerialPort::timeout(self);
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        SerialPort::set_timeout(self, timeout)?;
+        return Ok(());
+    }
+
+    fn baud_rate(&self) -> Result<u32, Error> {
+        return Ok(SerialPort::baud_rate(self)?);
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), Error> {
+        SerialPort::set_baud_rate(self, baud_rate)?;
+        return Ok(());
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn timeout(&self) -> Duration {
+        return self.as_ref().timeout();
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        return self.as_mut().set_timeout(timeout);
+    }
+
+    fn baud_rate(&self) -> Result<u32, Error> {
+        return self.as_ref().baud_rate();
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), Error> {
+        return self.as_mut().set_baud_rate(baud_rate);
+    }
+}
+
+/// One direction of the pipe
+///
+/// Every byte is tagged with baud rate of the sender, so receiver
+/// working at different speed does not get it, just like real UART
+/// loses bytes on baud rate mismatch
+///
+#[derive(Default)]
+struct Channel {
+    state: Mutex<ChannelState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct ChannelState {
+    data: VecDeque<(u8, u32)>,
+    closed: bool,
+}
+
+/// In-memory transport
+///
+/// One end of bidirectional pipe created by `pipe()`. Whatever is written
+/// to one end can be read from another. Read returns `TimedOut` error when
+/// no data arrives within timeout and end of file when the other end is
+/// dropped.
+///
+pub struct PipePort {
+    rx: Arc<Channel>,
+    tx: Arc<Channel>,
+    timeout: Duration,
+    baud_rate: u32,
+}
+
+/// Create pair of connected in-memory ports
+pub fn pipe() -> (PipePort, PipePort) {
+    let a = Arc::new(Channel::default());
+    let b = Arc::new(Channel::default());
+
+    let left = PipePort { rx: a.clone(), tx: b.clone(), timeout: Duration::from_millis(3000), baud_rate: 9600 };
+    let right = PipePort { rx: b, tx: a, timeout: Duration::from_millis(3000), baud_rate: 9600 };
+
+    return (left, right);
+}
+
+impl Read for PipePort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let deadline = Instant::now().checked_add(self.timeout);
+        let mut state = self.rx.state.lock().unwrap();
+
+        loop {
+            // drop everything sent at wrong speed
+            while let Some(&(_, baud_rate)) = state.data.front() {
+                if baud_rate == self.baud_rate {
+                    break;
+                }
+                state.data.pop_front();
+            }
+
+            if !state.data.is_empty() {
+                let mut len = 0;
+                while len < buf.len() {
+                    match state.data.front() {
+                        Some(&(byte, baud_rate)) if baud_rate == self.baud_rate => {
+                            buf[len] = byte;
+                            state.data.pop_front();
+                            len += 1;
+                        },
+                        _ => break,
+                    }
+                }
+                return Ok(len);
+            }
+
+            if state.closed {
+                return Ok(0);
+            }
+
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "Pipe read timed out"));
+                    }
+                    self.rx.ready.wait_timeout(state, deadline - now).unwrap().0
+                },
+                None => self.rx.ready.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl Write for PipePort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.tx.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Pipe closed"));
+        }
+
+        state.data.extend(buf.iter().map(|&byte| (byte, self.baud_rate)));
+        self.tx.ready.notify_all();
+
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl Drop for PipePort {
+    fn drop(&mut self) {
+        for channel in [&self.rx, &self.tx] {
+            channel.state.lock().unwrap().closed = true;
+            channel.ready.notify_all();
+        }
+    }
+}
+
+impl Transport for PipePort {
+    fn timeout(&self) -> Duration {
+        return self.timeout;
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.timeout = timeout;
+        return Ok(());
+    }
+
+    fn baud_rate(&self) -> Result<u32, Error> {
+        return Ok(self.baud_rate);
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), Error> {
+        self.baud_rate = baud_rate;
+        return Ok(());
+    }
+}
 
 pub trait IOMethods {
     fn write_buf(&mut self, buf: Vec<u8>) -> Result<(), Error>;
     fn write_str(&mut self, buf: &'static str) -> Result<(), Error>;
     fn write_u32(&mut self, buf: u32) -> Result<(), Error>;
-    fn read_buf(&mut self, len: usize) -> Result<Vec<u8>, Error>;
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, Error>;
     fn read_str(&mut self, len: usize) -> Result<String, Error>;
     fn read_u32(&mut self) -> Result<u32, Error>;
     fn read_byte(&mut self) -> Result<u8, Error>;
 }
 
-impl IOMethods for ComPort {
+impl<T: Transport + ?Sized> IOMethods for T {
     fn write_buf(&mut self, buf: Vec<u8>) -> Result<(), Error> {
         // println!("Write buf: {:0>2X?} {}", buf, String::from_utf8_lossy(&buf));
         // debug!("Write buf: {:0>2X?}", buf);
-        self.write(&buf)?;
+        self.write_all(&buf)?;
         std::io::stdout().flush().unwrap();
         return Ok(());
     }
@@ -57,28 +259,100 @@ impl IOMethods for ComPort {
         return self.write_buf(buf.to_le_bytes().to_vec());
     }
 
-    fn read_buf(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+    /// Read exactly `len` bytes
+    ///
+    /// Port is read repeatedly until all the data arrives or port timeout
+    /// expires, so slow data coming in pieces is not lost.
+    ///
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, Error> {
         let mut buf: Vec<u8> = vec![0; len];
-        self.read(buf.as_mut_slice())?;
-        // println!("Read buf: {:0>2X?} {}", buf, std::str::from_utf8_unchecked(&buf));
+        let deadline = Instant::now().checked_add(self.timeout());
+        let mut received = 0;
+
+        while received < len {
+            match self.read(&mut buf[received..]) {
+                Ok(0) => return Err(Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof))),
+                Ok(n) => received += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut => {
+                    debug!(" Read timeout: {:0>2X?}", &buf[..received]);
+                    return Err(Error::Timeout { expected: len, received });
+                },
+                Err(err) => return Err(Error::Io(err)),
+            }
+
+            if received < len && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                debug!(" Read timeout: {:0>2X?}", &buf[..received]);
+                return Err(Error::Timeout { expected: len, received });
+            }
+        }
+
         // println!("Read buf: {:0>2X?} {}", buf, String::from_utf8_lossy(&buf));
         // debug!(" Read buf: {:0>2X?}", buf);
         return Ok(buf);
     }
 
     fn read_str(&mut self, len: usize) -> Result<String, Error> {
-        let res = self.read_buf(len)?;
+        let res = self.read_bytes(len)?;
         return Ok(String::from_utf8_lossy(&res).to_string());
     }
 
     fn read_u32(&mut self) -> Result<u32, Error> {
-        let res = self.read_buf(4)?;
+        let res = self.read_bytes(4)?;
         return Ok(u32::from_le_bytes([res[0], res[1], res[2], res[3]]));
     }
 
     fn read_byte(&mut self) -> Result<u8, Error> {
-        let res = self.read_buf(1)?;
+        let res = self.read_bytes(1)?;
         return Ok(res[0]);
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipe_passes_data_both_ways() {
+        let (mut left, mut right) = pipe();
+
+        left.write_buf(vec![1, 2, 3]).unwrap();
+        assert_eq!(right.read_bytes(3).unwrap(), vec![1, 2, 3]);
+
+        right.write_u32(0x12345678).unwrap();
+        assert_eq!(left.read_u32().unwrap(), 0x12345678);
+    }
+
+    #[test]
+    fn short_read_times_out() {
+        let (mut left, mut right) = pipe();
+        right.set_timeout(Duration::from_millis(50)).unwrap();
+
+        left.write_buf(vec![1, 2]).unwrap();
+        match right.read_bytes(4) {
+            Err(Error::Timeout { expected, received }) => {
+                assert_eq!(expected, 4);
+                assert_eq!(received, 2);
+            },
+            res => panic!("Timeout expected, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn bytes_sent_at_other_baud_rate_are_lost() {
+        let (mut left, mut right) = pipe();
+        right.set_timeout(Duration::from_millis(50)).unwrap();
+
+        left.set_baud_rate(115200).unwrap();
+        left.write_buf(vec![1, 2]).unwrap();
+        assert!(matches!(right.read_bytes(1), Err(Error::Timeout { received: 0, .. })));
+    }
+
+    #[test]
+    fn closed_pipe_is_end_of_file() {
+        let (left, mut right) = pipe();
+        drop(left);
+
+        assert!(matches!(right.read_bytes(1), Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof));
+    }
+}
@@ -2,22 +2,61 @@
 ///
 use std::io;
 use std::fmt;
+use std::ops::Range;
+use std::time::Duration;
 
-use indicatif::ProgressIterator;
+use indicatif::{
+    ProgressBar,
+    ProgressIterator,
+};
 
 use crate::{
     com_port::{
         self,
-        ComPort,
+        Transport,
         IOMethods,
     },
-    firmware::HexFile,
+    firmware::{
+        HexFile,
+        Block,
+    },
+    target::Chip,
 };
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     SerialPort(com_port::Error),
+    /// Boot loader returned wrong control sum of written chunk
+    Checksum { expected: u8, actual: u8 },
+    /// Boot loader does not answer with its identifier string
+    Sync(String),
+    /// None of baud rates works stable
+    Baud,
+    /// Boot loader code read back from RAM differs from uploaded one
+    LoaderMismatch { addr: u32, expected: u8, actual: u8 },
+    /// Boot loader answers 'I' with unknown identifier
+    LoaderId(String),
+    /// Boot loader does not echo extension command
+    Unsupported(char),
+    /// Flash content differs from image
+    Verify(Mismatch),
+    /// All the differences found when verify does not stop on the first one
+    Mismatches(Vec<Mismatch>),
+}
+
+/// Flash byte differing from image
+#[derive(Debug, Clone, Copy)]
+pub struct Mismatch {
+    pub addr: u32,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "adr=0x{:0>8x} dataw=0x{:0>2x} datar=0x{:0>2x}", self.addr, self.expected, self.actual)
+    }
 }
 
 impl fmt::Display for Error {
@@ -25,6 +64,18 @@ impl fmt::Display for Error {
         match *self {
             Error::Io(ref err) => write!(f, "[{}]", err),
             Error::SerialPort(ref err) => write!(f, "Serial port error: {}", err),
+            Error::Checksum { expected, actual } =>
+                write!(f, "Control sum error expected 0x{:0>2X?} got 0x{:0>2X?}", expected, actual),
+            Error::Sync(ref id) => write!(f, "Unable to resync boot loader, got {:?}", id),
+            Error::Baud => write!(f, "No stable baud rate found, reset the board and try lower one"),
+            Error::LoaderMismatch { addr, expected, actual } =>
+                write!(f, "Boot loader verify failed adr=0x{:0>8x} dataw=0x{:0>2x} datar=0x{:0>2x}", addr, expected, actual),
+            Error::LoaderId(ref id) => write!(f, "Unknown boot loader identifier {:?}, expected {:?}", id, LOADER_ID),
+            Error::Unsupported(cmd) =>
+                write!(f, "Boot loader does not support '{}' command, use custom one with --loader", cmd),
+            Error::Verify(mismatch) => write!(f, "Verify failed {}", mismatch),
+            Error::Mismatches(ref list) =>
+                write!(f, "Verify failed, {} bytes differ, first one {}", list.len(), list[0]),
         }
     }
 }
@@ -41,13 +92,19 @@ impl From<com_port::Error> for Error {
     }
 }
 
+/// Identifier string returned by RAM boot loader 'I' command
+const LOADER_ID: &str = "1986BOOTUART";
+
+/// Cortex-M CPUID register address
+const CPUID_ADDR: u32 = 0xE000ED00;
+
 /// Check if com port alive
 ///
 /// Send 512 zero bytes and check if port answer
 ///
-pub fn check_port(port: &mut ComPort) -> Result<(), Error> {
+pub fn check_port<T: Transport + ?Sized>(port: &mut T) -> Result<(), Error> {
     port.write_buf(vec![0; 512])?; // write 512 zero bytes
-    port.read_buf(3)?; // try to read 3 ones
+    port.read_bytes(3)?; // try to read 3 ones
 
     return Ok(());
 }
@@ -58,7 +115,7 @@ pub fn check_port(port: &mut ComPort) -> Result<(), Error> {
 /// baud rate with this function.
 ///
 /// After success reconnect required with given baud rate
-pub fn set_baud_rate(port: &mut ComPort, baud_rate: u32) -> Result<(), Error> {
+pub fn set_baud_rate<T: Transport + ?Sized>(port: &mut T, baud_rate: u32) -> Result<(), Error> {
     // write 'B' b1 b2 b3 0x0
     // where b1 low byte of baud rate 
     //       b3 hight byte of baud rate value
@@ -67,64 +124,159 @@ pub fn set_baud_rate(port: &mut ComPort, baud_rate: u32) -> Result<(), Error> {
     port.write_buf(vec![0xD])?;
 
     // try to read any response value
-    port.read_buf(1)?;
+    port.read_bytes(1)?;
 
     return Ok(());
 }
 
-pub fn read_baud_rate(port: &mut ComPort) -> Result<Vec<u8>, Error> {
+pub fn read_baud_rate<T: Transport + ?Sized>(port: &mut T) -> Result<Vec<u8>, Error> {
     port.write_buf(vec![0xD])?;
 
-    let resp = port.read_buf(3)?;
+    let resp = port.read_bytes(3)?;
     if resp != [0xD, 0xA, 0x3E] {
-        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "Error setting baud rate")));
+        return Err(Error::Io(io::Error::other("Error setting baud rate")));
     }
 
     return Ok(resp);
 }
 
+/// Find the fastest baud rate board works stable on
+///
+/// Rates are tried in given order, so the fastest one should go first.
+/// Board and port are expected to work on the port baud rate at start.
+///
+/// Board switches to a new rate even if the line fails to work on it, so
+/// after failure the next rate is requested both at the failed rate and
+/// at the last working one. Rates the port itself can't work at are
+/// skipped before the board is asked for them.
+///
+/// Port is left at the found baud rate.
+///
+pub fn negotiate_baud_rate<T: Transport + ?Sized>(port: &mut T, rates: &[u32]) -> Result<u32, Error> {
+    let timeout = port.timeout();
+    port.set_timeout(Duration::from_millis(300))?;
+
+    let good = port.baud_rate()?;
+    let mut last = good;
+    let mut res = Err(Error::Baud);
+
+    'rates: for &rate in rates {
+        if let Err(err) = check_port_rate(port, rate) {
+            debug!("Port can't work at baud rate {}: {}", rate, err);
+            continue;
+        }
+
+        let from = if last == good { vec![good] } else { vec![last, good] };
+        for from_rate in from {
+            debug!("Try baud rate {} from {}", rate, from_rate);
+            match switch_baud_rate(port, from_rate, rate) {
+                Ok(()) => {
+                    res = Ok(rate);
+                    break 'rates;
+                },
+                Err(err) => debug!("Baud rate {} failed: {}", rate, err),
+            }
+        }
+        last = rate;
+    }
+
+    port.set_timeout(timeout)?;
+    return res;
+}
+
+/// Check the port can work at given baud rate
+///
+/// Port is switched to the rate and back, so the board is never asked for
+/// a rate the port can't follow it to
+///
+pub fn check_port_rate<T: Transport + ?Sized>(port: &mut T, rate: u32) -> Result<(), Error> {
+    let current = port.baud_rate()?;
+    let res = port.set_baud_rate(rate);
+    port.set_baud_rate(current)?;
+
+    return Ok(res?);
+}
+
+/// Move board and port from one baud rate to another and check the prompt
+fn switch_baud_rate<T: Transport + ?Sized>(port: &mut T, from: u32, to: u32) -> Result<(), Error> {
+    port.set_baud_rate(from)?;
+    drain(port)?;
+
+    if to != from {
+        // echo could be lost even if the board got the command
+        if let Err(err) = set_baud_rate(port, to) {
+            debug!("No answer to baud rate change: {}", err);
+        }
+        port.set_baud_rate(to)?;
+        drain(port)?;
+    }
+
+    // prompt must come back every time
+    for _ in 0..3 {
+        read_baud_rate(port)?;
+    }
+
+    return Ok(());
+}
+
 /// Upload UART boot loader to board RAM
 ///
 /// Custom boot loader loaded into RAM to provide additional capabilities
 /// of loading real program code to flash memory
 ///
-/// Boot loader uploaded to base address 0x20000000
+/// Boot loader is uploaded to RAM address its image starts at and is
+/// started from there
+///
+/// Before start the whole boot loader is read back by 8 byte blocks and
+/// compared with uploaded code
 ///
-pub fn boot_load(port: &mut ComPort, data: HexFile) -> Result<(), Error> {
-    // println!("Writing boot code to {:0>8X?}", data.addr);
-    // println!("Data size is {} bytes", data.size);
+pub fn boot_load<T: Transport + ?Sized>(port: &mut T, data: HexFile) -> Result<(), Error> {
+    // boot loader is sent as one piece so close all the gaps
+    let data = data.contiguous(0xFF);
+    let size = data.data.len() as u32;
 
     // set address where to put boot loader
     port.write_str("L")?;
     port.write_u32(data.addr)?; // address to load code to 
-    port.write_u32(data.size)?; // size of data
+    port.write_u32(size)?; // size of data
     if port.read_str(1)? != "L" {
-        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "Error setting baud rate")));
+        return Err(Error::Io(io::Error::other("Error loading boot code")));
     }
 
-    // write boot loader code file 1986_BOOT_UART.hex
-    port.write_buf(data.buf)?;
+    // write boot loader code
+    port.write_buf(data.data.clone())?;
     if port.read_str(1)? != "K" {
-        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "Error writing boot code")));
+        return Err(Error::Io(io::Error::other("Error writing boot code")));
     }
     
-    // read and compare
-    // TODO: read and check throught all the data
-    port.write_str("Y")?;
-    port.write_u32(data.addr)?;  // address to load code to 
-    port.write_u32(0x8u32)?;     // not sure what is it
+    // read and compare by 8 byte blocks
+    for (i, vbuf) in data.data.chunks(READ_SIZE as usize).enumerate() {
+        let addr = data.addr + i as u32 * READ_SIZE;
+        port.write_str("Y")?;
+        port.write_u32(addr)?;
+        port.write_u32(vbuf.len() as u32)?;
+
+        let resp = port.read_bytes(vbuf.len() + 2)?;
+        if resp[0] != b'Y' || resp[vbuf.len() + 1] != b'K' {
+            return Err(Error::Io(io::Error::other(format!("Error reading written code at 0x{:0>8X?}", addr))));
+        }
 
-    let resp = port.read_buf(10)?; 
-    if resp[0] != ('Y' as u8) && resp[9] != ('K' as u8) {
-        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "Error reading written code")));
+        let rbuf = &resp[1..vbuf.len() + 1];
+        if let Some(pos) = (0..vbuf.len()).find(|&pos| rbuf[pos] != vbuf[pos]) {
+            return Err(Error::LoaderMismatch {
+                addr: addr + pos as u32,
+                expected: vbuf[pos],
+                actual: rbuf[pos],
+            });
+        }
     }
 
-    // run code
+    // run code, 'R' takes only the address, anything sent after it goes
+    // to the started boot loader as commands
     port.write_str("R")?;
-    port.write_u32(data.addr)?; // address to load code to 
-    port.write_u32(data.size)?; // size of data
+    port.write_u32(data.addr)?; // address to start code from
     if port.read_str(1)? != "R" {
-        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "Error running boot code")));
+        return Err(Error::Io(io::Error::other("Error running boot code")));
     }
     
     return Ok(());
@@ -133,97 +285,462 @@ pub fn boot_load(port: &mut ComPort, data: HexFile) -> Result<(), Error> {
 /// Read boot loader info 
 ///
 /// Really this is a last 12 bytes of boot loader
-/// Normally it should return 1986BOOTUART string
+/// Normally it should return 1986BOOTUART string, anything else means
+/// boot loader is not running or it is not the one we talk to
 ///
-pub fn read_info(port: &mut ComPort) -> Result<String, Error> {
+pub fn read_info<T: Transport + ?Sized>(port: &mut T) -> Result<String, Error> {
     port.write_str("I")?;
-    let res = port.read_str(12)?;
+    let res = port.read_str(LOADER_ID.len())?;
+    if res != LOADER_ID {
+        return Err(Error::LoaderId(res));
+    }
 
     return Ok(res);
 }
 
+/// Read 32 bit word from any chip address
+///
+/// Needs 'W' extension command, see PROTOCOL.md. Stock loader 'V' reads
+/// flash only, 'A' does not even accept an address out of flash.
+///
+pub fn read_word<T: Transport + ?Sized>(port: &mut T, addr: u32) -> Result<u32, Error> {
+    start_extension(port, b'W')?;
+    port.write_u32(addr)?;
+
+    return Ok(port.read_u32()?);
+}
+
+/// Read CPU core identification register
+///
+/// Needs 'W' extension command, see PROTOCOL.md
+///
+pub fn read_cpuid<T: Transport + ?Sized>(port: &mut T) -> Result<u32, Error> {
+    return read_word(port, CPUID_ADDR);
+}
+
+/// Run program
+///
+/// Boot loader starts program from flash and answers with 'R'
+///
+pub fn run<T: Transport + ?Sized>(port: &mut T) -> Result<(), Error> {
+    port.write_str("R")?;
+    if port.read_str(1)? != "R" {
+        return Err(Error::Io(io::Error::other("Error running program")));
+    }
+
+    return Ok(());
+}
+
+/// Run program from given address
+///
+/// Needs 'J' extension command, see PROTOCOL.md
+///
+pub fn run_at<T: Transport + ?Sized>(port: &mut T, addr: u32) -> Result<(), Error> {
+    start_extension(port, b'J')?;
+    port.write_u32(addr)?;
+    if port.read_str(1)? != "K" {
+        return Err(Error::Io(io::Error::other(format!("Error running program at 0x{:0>8X?}", addr))));
+    }
+
+    return Ok(());
+}
+
 /// Erase
 ///
 /// Full chip erase
 ///
-pub fn erase(port: &mut ComPort) -> Result<(), Error> {
+/// Boot loader checks flash after erase and returns address of the first
+/// not erased word, so the whole flash is clean if it is the end of flash
+///
+pub fn erase<T: Transport + ?Sized>(port: &mut T, chip: &Chip) -> Result<(), Error> {
     // set address where to put boot loader
     port.write_str("E")?;
     // pause 1000
     if port.read_str(1)? != "E" {
-        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "Error setting baud rate")));
+        return Err(Error::Io(io::Error::other("Error setting baud rate")));
     }
 
     let addr = port.read_u32()?;
     let data = port.read_u32()?;
 
-    if (addr == 0x08020000) && (data == 0xffffffff) {
+    if (addr == chip.memory.flash().end()) && (data == 0xffffffff) {
         return Ok(());
     } else {
-        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, format!("Chip erase fail addr=0x{:0>4X?} data={:0>4X?}", addr, data))));
+        return Err(Error::Io(io::Error::other(format!("Chip erase fail addr=0x{:0>4X?} data={:0>4X?}", addr, data))));
     }
 }
 
-/// Upload real firmware to flash
+/// Time to wait for extension command echo
+const PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Start boot loader extension command
 ///
-pub fn program(port: &mut ComPort, data: &HexFile) -> Result<(), Error> {
-    // println!("Writing program code to {:0>8X?}", data.addr);
-    // println!("Data size is {} bytes", data.size);
+/// Extension commands are echoed by boot loader before arguments are sent.
+/// Stock boot loader silently ignores unknown commands, so missing echo
+/// means the command is not supported.
+///
+fn start_extension<T: Transport + ?Sized>(port: &mut T, cmd: u8) -> Result<(), Error> {
+    let timeout = port.timeout();
+    port.set_timeout(PROBE_TIMEOUT)?;
+    port.write_buf(vec![cmd])?;
+    let res = port.read_byte();
+    port.set_timeout(timeout)?;
+
+    match res {
+        Ok(echo) if echo == cmd => return Ok(()),
+        Ok(echo) => return Err(Error::Io(io::Error::other(format!("Wrong echo 0x{:0>2X?} of '{}' command", echo, cmd as char)))),
+        Err(com_port::Error::Timeout { .. }) => return Err(Error::Unsupported(cmd as char)),
+        Err(err) => return Err(err.into()),
+    }
+}
+
+/// Erase single flash page
+///
+/// Needs 'S' extension command, see PROTOCOL.md
+///
+pub fn erase_page<T: Transport + ?Sized>(port: &mut T, addr: u32) -> Result<(), Error> {
+    start_extension(port, b'S')?;
+    port.write_u32(addr)?;
+    if port.read_str(1)? != "K" {
+        return Err(Error::Io(io::Error::other(format!("Error erasing page 0x{:0>8X?}", addr))));
+    }
 
-    // set address where to put program
-    port.write_str("A")?;
-    port.write_u32(data.addr)?; // address to load code to 
-    if port.read_byte()? != 0x08 {
-        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "Error setting flash address")));
-    }
-
-    // write code by 256 byte length chunks
-    // let mut iter = data.buf.chunks(256);
-    // while match iter.next() { 
-    //     None => false,
-    //     Some(wbuf) => {
-    //       let res =  write_program_chunk(port, wbuf)?;
-    //       res
-    //     }
-    // } {};
-
-    // write code by 256 byte length chunks
-    let iter = data.buf.chunks(256);
-    for wbuf in iter.progress() { 
-       write_program_chunk(port, wbuf)?;
-    };
-    
     return Ok(());
 }
 
-/// Verify uploaded firmware
+/// Erase flash pages starting at given addresses
+pub fn erase_pages<T: Transport + ?Sized>(port: &mut T, pages: &[u32]) -> Result<(), Error> {
+    for &page in pages.iter().progress() {
+        erase_page(port, page)?;
+    }
+
+    return Ok(());
+}
+
+/// Size of data chunk written by single 'P' command
+const CHUNK_SIZE: u32 = 256;
+
+/// Size of data block returned by single 'V' command, also used for 'Y' read back
+const READ_SIZE: u32 = 8;
+
+/// Set flash address for the next 'P' or 'V' commands
+///
+/// Boot loader answers with 8 bit sum of the address bytes, it is 0x08
+/// only for 0x08000000
 ///
-pub fn verify(port: &mut ComPort, data: &HexFile) -> Result<(), Error> {
-    // set address where to put program
+fn set_address<T: Transport + ?Sized>(port: &mut T, addr: u32) -> Result<(), Error> {
     port.write_str("A")?;
-    port.write_u32(data.addr)?; // address to load code to 
-    if port.read_byte()? != 0x08 {
-        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "Error setting flash address")));
-    }
-
-    // // write code by 256 byte length chunks
-    // let mut iter = data.buf.chunks(256);
-    // while match iter.next() { 
-    //     None => false,
-    //     Some(wbuf) => {
-    //       let res =  verify_program_chunk(port, wbuf)?;
-    //       res
-    //     }
-    // } {};
-
-    let iter = data.buf.chunks(256);
-    for wbuf in iter.progress() { 
-       verify_program_chunk(port, wbuf)?;
-    };
-    
+    port.write_u32(addr)?;
+    if port.read_byte()? != checksum(&addr.to_le_bytes()) {
+        return Err(Error::Io(io::Error::other(format!("Error setting flash address 0x{:0>8X?}", addr))));
+    }
+
     return Ok(());
 }
 
+/// CRC32 of memory range calculated by boot loader
+///
+/// Needs 'C' extension command, see PROTOCOL.md
+///
+pub fn read_crc<T: Transport + ?Sized>(port: &mut T, addr: u32, len: u32) -> Result<u32, Error> {
+    start_extension(port, b'C')?;
+    port.write_u32(addr)?;
+    port.write_u32(len)?;
+
+    return Ok(port.read_u32()?);
+}
+
+/// Number of 256 byte chunks in all the blocks
+fn chunk_count(blocks: &[Block]) -> u64 {
+    return blocks.iter().map(|block| block.data.len() as u64 / CHUNK_SIZE as u64).sum();
+}
+
+/// Upload real firmware to flash
+///
+/// Image is sent by 256 byte chunks, chunk bytes not covered by image are
+/// set to `fill` value. When flash is freshly `erased` chunks
+/// consisting of 0xFF only are already there and are skipped. Boot loader
+/// increments address after every chunk, so 'A' command is sent only when
+/// the next chunk does not follow the previous one.
+///
+/// Failed chunk is sent again up to `retries` times. Before each retry
+/// boot loader is resynchronised and flash address is set to the chunk
+/// start again. Programming can only clear bits and the failed attempt
+/// could already burn wrong data, so retried chunk is read back with 'V'
+/// and any difference is a hard error - only a page erase could fix it.
+///
+pub fn program<T: Transport + ?Sized>(port: &mut T, data: &HexFile, fill: u8, retries: u32, erased: bool) -> Result<(), Error> {
+    let blocks = data.blocks(CHUNK_SIZE, fill);
+    let chunks = blocks.iter()
+        .flat_map(|block| block.data.chunks(CHUNK_SIZE as usize).enumerate()
+            .map(move |(i, chunk)| (block.addr + i as u32 * CHUNK_SIZE, chunk)))
+        .filter(|(_, chunk)| !erased || chunk.iter().any(|&byte| byte != 0xFF))
+        .collect::<Vec<(u32, &[u8])>>();
+
+    let total = chunk_count(&blocks) as usize;
+    debug!("Writing {} of {} chunks", chunks.len(), total);
+
+    let pb = ProgressBar::new(chunks.len() as u64);
+    let mut next_addr = None;
+
+    for &(addr, wbuf) in &chunks {
+        if next_addr != Some(addr) {
+            set_address(port, addr)?;
+        }
+
+        let mut attempt = 0;
+        while let Err(err) = write_program_chunk(port, wbuf) {
+            if attempt >= retries {
+                return Err(err);
+            }
+            attempt += 1;
+
+            pb.println(format!("    Chunk 0x{:0>8X?} failed: {}, retry {}/{}", addr, err, attempt, retries));
+            resync(port)?;
+            set_address(port, addr)?;
+        }
+        next_addr = Some(addr + CHUNK_SIZE);
+
+        // flash holds all the attempts written over each other
+        if attempt > 0 {
+            set_address(port, addr)?;
+            if let Some(&mismatch) = verify_program_chunk(port, data, addr, wbuf)?.first() {
+                return Err(Error::Verify(mismatch));
+            }
+            next_addr = None;
+        }
+        pb.inc(1);
+    }
+    pb.finish();
+
+    if chunks.len() < total {
+        println!("    Skipped {} blank chunks of {}", total - chunks.len(), total);
+    }
+
+    return Ok(());
+}
+
+/// Bring RAM boot loader back to command mode
+///
+/// Boot loader could be stuck in the middle of 'P' command waiting for the
+/// rest of the data. Chunk of 0xFF bytes completes it - loader ignores 0xFF
+/// as unknown command if it is not inside 'P'. Bytes received before are
+/// still programmed, possibly at shifted positions, so flash content of the
+/// chunk is unknown afterwards and has to be checked by the caller.
+/// Then the line is drained and 'I' command must return identifier string.
+///
+pub fn resync<T: Transport + ?Sized>(port: &mut T) -> Result<(), Error> {
+    port.write_buf(vec![0xFF; CHUNK_SIZE as usize])?;
+    drain(port)?;
+
+    port.write_str("I")?;
+    let id = port.read_str(LOADER_ID.len())?;
+    if id != LOADER_ID {
+        return Err(Error::Sync(id));
+    }
+
+    return Ok(());
+}
+
+/// Throw away everything received until the line goes quiet
+fn drain<T: Transport + ?Sized>(port: &mut T) -> Result<(), Error> {
+    let timeout = port.timeout();
+    port.set_timeout(Duration::from_millis(100))?;
+
+    let mut buf = [0u8; 64];
+    let res = loop {
+        match port.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => debug!("Drop {:0>2X?}", &buf[..n]),
+            Err(ref err) if err.kind() == io::ErrorKind::TimedOut => break Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => break Err(Error::Io(err)),
+        }
+    };
+
+    port.set_timeout(timeout)?;
+    return res;
+}
+
+/// Verify uploaded firmware
+///
+/// Stops on the first wrong chunk unless `all` mismatches are requested,
+/// then the whole image is read back and every wrong byte is reported
+///
+pub fn verify<T: Transport + ?Sized>(port: &mut T, data: &HexFile, all: bool) -> Result<(), Error> {
+    let blocks = data.blocks(CHUNK_SIZE, 0xFF);
+    let pb = ProgressBar::new(chunk_count(&blocks));
+    let mut mismatches: Vec<Mismatch> = vec![];
+
+    'blocks: for block in &blocks {
+        debug!("Verify block 0x{:0>8X?} {} bytes", block.addr, block.data.len());
+        set_address(port, block.addr)?;
+
+        for (i, wbuf) in block.data.chunks(CHUNK_SIZE as usize).enumerate() {
+            mismatches.extend(verify_program_chunk(port, data, block.addr + i as u32 * CHUNK_SIZE, wbuf)?);
+            pb.inc(1);
+
+            if !all && !mismatches.is_empty() {
+                break 'blocks;
+            }
+        }
+    }
+    pb.finish();
+
+    return check_mismatches(mismatches, all);
+}
+
+/// Turn mismatches found into verify result
+fn check_mismatches(mismatches: Vec<Mismatch>, all: bool) -> Result<(), Error> {
+    match mismatches.first() {
+        None => return Ok(()),
+        Some(&first) if !all => return Err(Error::Verify(first)),
+        Some(_) => return Err(Error::Mismatches(mismatches)),
+    }
+}
+
+/// Compare flash content with the image by CRC32
+///
+/// Image data of every page is checked with boot loader CRC32 command,
+/// only pages with wrong CRC are read back to find mismatch address.
+/// Whole image is read back if boot loader can't calculate CRC. With `all`
+/// it goes on after the first wrong page and reports mismatches of all of
+/// them, pages with correct CRC are never read back.
+///
+pub fn verify_crc<T: Transport + ?Sized>(port: &mut T, data: &HexFile, page_size: u32, all: bool) -> Result<(), Error> {
+    let blocks = data.blocks(page_size, 0xFF);
+    let count = blocks.iter().map(|block| block.data.len() as u64 / page_size as u64).sum();
+    let pb = ProgressBar::new(count);
+    let mut mismatches: Vec<Mismatch> = vec![];
+
+    'blocks: for block in &blocks {
+        for (i, page) in block.data.chunks(page_size as usize).enumerate() {
+            let addr = block.addr + i as u32 * page_size;
+
+            // image data of the page, padding is not checked
+            for seg in &data.segments {
+                let start = seg.addr.max(addr);
+                let end = seg.end().min(addr + page_size);
+                if start >= end {
+                    continue;
+                }
+
+                let expected = crc32fast::hash(&page[(start - addr) as usize..(end - addr) as usize]);
+                match read_crc(port, start, end - start) {
+                    Ok(crc) if crc == expected => {},
+                    Ok(crc) => {
+                        debug!("CRC mismatch 0x{:0>8X?}..0x{:0>8X?} {:0>8X?} != {:0>8X?}", start, end, crc, expected);
+                        set_address(port, addr)?;
+                        mismatches.extend(verify_program_chunk(port, data, addr, page)?);
+                        // the whole page is read back already
+                        break;
+                    },
+                    Err(Error::Unsupported(_)) => {
+                        pb.finish_and_clear();
+                        println!("    Boot loader can't calculate CRC, reading flash back");
+                        return verify(port, data, all);
+                    },
+                    Err(err) => return Err(err),
+                }
+            }
+            pb.inc(1);
+
+            if !all && !mismatches.is_empty() {
+                break 'blocks;
+            }
+        }
+    }
+    pb.finish();
+
+    return check_mismatches(mismatches, all);
+}
+
+/// Read flash memory content
+///
+/// Data is read by 8 byte blocks with 'V' command starting from `addr`
+///
+pub fn read_flash<T: Transport + ?Sized>(port: &mut T, addr: u32, len: u32) -> Result<Vec<u8>, Error> {
+    set_address(port, addr)?;
+
+    let count = len.div_ceil(READ_SIZE);
+    let mut buf: Vec<u8> = Vec::with_capacity((count * READ_SIZE) as usize);
+
+    for _ in (0..count).progress() {
+        port.write_str("V")?;
+        let mut rbuf = port.read_bytes(READ_SIZE as usize)?;
+        buf.append(&mut rbuf);
+    }
+
+    buf.truncate(len as usize);
+    return Ok(buf);
+}
+
+/// Find flash pages which differ from image
+///
+/// All the pages image lies in are read back and only image bytes are
+/// compared. Changed pages are returned whole: current flash content with
+/// image data put over it, so the rest of the page survives page erase.
+///
+pub fn changed_pages<T: Transport + ?Sized>(port: &mut T, data: &HexFile, page_size: u32) -> Result<Vec<Block>, Error> {
+    let mut changed: Vec<Block> = vec![];
+
+    for block in data.blocks(page_size, 0xFF) {
+        let flash = read_flash(port, block.addr, block.data.len() as u32)?;
+
+        let pages = block.data.chunks(page_size as usize).zip(flash.chunks(page_size as usize));
+        for (i, (page, old)) in pages.enumerate() {
+            let addr = block.addr + i as u32 * page_size;
+            let mut buf = old.to_vec();
+            let mut differs = false;
+
+            for (pos, &byte) in page.iter().enumerate() {
+                if buf[pos] != byte && data.contains(addr + pos as u32) {
+                    buf[pos] = byte;
+                    differs = true;
+                }
+            }
+
+            if differs {
+                changed.push(Block { addr, data: buf });
+            }
+        }
+    }
+
+    return Ok(changed);
+}
+
+/// Find flash areas which are not erased
+///
+/// Flash is read with 'V' commands and bytes other than 0xFF are returned
+/// as address ranges, see `not_blank_ranges`
+///
+pub fn blank_check<T: Transport + ?Sized>(port: &mut T, addr: u32, len: u32) -> Result<Vec<Range<u32>>, Error> {
+    let buf = read_flash(port, addr, len)?;
+
+    return Ok(not_blank_ranges(addr, &buf));
+}
+
+/// Address ranges of bytes other than 0xFF in memory read from `addr`
+///
+/// Programmed code has plenty of 0xFF bytes inside, so ranges closer than
+/// a chunk to each other are merged into one
+///
+fn not_blank_ranges(addr: u32, buf: &[u8]) -> Vec<Range<u32>> {
+    let mut ranges: Vec<Range<u32>> = vec![];
+    for (i, &byte) in buf.iter().enumerate() {
+        if byte == 0xFF {
+            continue;
+        }
+
+        let pos = addr + i as u32;
+        match ranges.last_mut() {
+            Some(range) if pos - range.end < CHUNK_SIZE => range.end = pos + 1,
+            _ => ranges.push(pos..pos + 1),
+        }
+    }
+
+    return ranges;
+}
 
 /// calculate checksum of data chunk
 ///
@@ -233,69 +750,76 @@ fn checksum(buf : &[u8]) -> u8 {
   return  buf.iter().fold(0, |acc, &x| acc.wrapping_add(x));
 }
 
-fn write_program_chunk(port: &mut ComPort, buf : &[u8]) ->  Result<bool, Error>  {
-    // if not a full 256 bytes buffer - fill the rest with 0xFF
-    let mut wbuf = buf.to_vec().clone();
-
-    let diff = 256 - buf.len(); // number of bytes up to 256
-    if diff > 0 {
-        debug!("Add {} of zero bytes up to 256 bytes length", diff);
-        wbuf.append(&mut vec![0x00; diff]);
-    }
+/// Write 256 byte chunk at current boot loader address
+///
+/// Chunk must be full, image blocks are already padded with fill value
+///
+fn write_program_chunk<T: Transport + ?Sized>(port: &mut T, wbuf : &[u8]) ->  Result<bool, Error>  {
+    debug_assert_eq!(wbuf.len(), CHUNK_SIZE as usize);
 
     debug!("Writing chunk");
     port.write_str("P")?;
     port.write_buf(wbuf.to_vec())?;
 
-    let sum : u8 = checksum(&wbuf);    // calcuate by written data
+    let sum : u8 = checksum(wbuf);    // calcuate by written data
     let rsum : u8 = port.read_byte()?; // return from UART
 
     debug!("Checking control sum {:0>2X?} == {:0>2X?}", sum, rsum);
     if rsum != sum {
-        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "Error checking control sum")));
+        return Err(Error::Checksum { expected: sum, actual: rsum });
     }
 
     Ok(true)
 }
 
-fn verify_program_chunk(port: &mut ComPort, buf : &[u8]) ->  Result<bool, Error>  {
+/// Compare chunk at current boot loader address with image
+///
+/// Only bytes of the image are compared, padding written with fill value
+/// is ignored. Returns all the wrong bytes of the chunk.
+///
+fn verify_program_chunk<T: Transport + ?Sized>(port: &mut T, data: &HexFile, addr: u32, buf : &[u8]) ->  Result<Vec<Mismatch>, Error>  {
     debug!("Verify chunk");
-    
+    let mut mismatches: Vec<Mismatch> = vec![];
+
     // check 32 chunks of 8 bytes blocks
-    // let mut iter = buf.chunks(8);
-    // while match iter.next() { 
-    //     None => false,
-    //     Some(vbuf) => {
-    //         port.write_str("V")?;
-    //         // std::thread::sleep(Duration::from_secs(1));
-    //         let rbuf = port.read_buf(8)?;
-    //         debug!("Verify -> {:0>2X?}", vbuf);
-    //         debug!("       <- {:0>2X?}", rbuf);
-    //
-    //         let buf_len = vbuf.len();
-    //         if rbuf[0..buf_len] != vbuf[0..buf_len] {
-    //             return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "Error verify block")));
-    //         }
-    //
-    //         true
-    //     }
-    // } {};
-
-    let iter = buf.chunks(8);
-    for vbuf in iter { 
+    for (i, vbuf) in buf.chunks(READ_SIZE as usize).enumerate() {
         port.write_str("V")?;
-        // std::thread::sleep(Duration::from_secs(1));
-        let rbuf = port.read_buf(8)?;
+        let rbuf = port.read_bytes(READ_SIZE as usize)?;
         debug!("Verify -> {:0>2X?}", vbuf);
         debug!("       <- {:0>2X?}", rbuf);
 
-        let buf_len = vbuf.len();
-        if rbuf[0..buf_len] != vbuf[0..buf_len] {
-            return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "Error verify block")));
+        let vaddr = addr + i as u32 * READ_SIZE;
+        for (pos, &expected) in vbuf.iter().enumerate() {
+            let actual = rbuf[pos];
+            if actual != expected && data.contains(vaddr + pos as u32) {
+                mismatches.push(Mismatch { addr: vaddr + pos as u32, expected, actual });
+            }
         }
     }
 
-    debug!("");
+    return Ok(mismatches);
+}
 
-    Ok(true)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_blank_ranges_merge_short_gaps() {
+        let mut buf = vec![0xFF; 0x1000];
+        // code with 0xFF bytes inside
+        buf[0x10..0x20].fill(0x00);
+        buf[0x18] = 0xFF;
+        buf[0x20 + 0xFF] = 0x01;
+        // a chunk of 0xFF apart
+        buf[0x120 + CHUNK_SIZE as usize] = 0x02;
+        buf[0xFFF] = 0x03;
+
+        assert_eq!(not_blank_ranges(0x08000000, &buf), vec![
+            0x08000010..0x08000120,
+            0x08000220..0x08000221,
+            0x08000FFF..0x08001000,
+        ]);
+        assert!(not_blank_ranges(0x08000000, &[0xFF; 16]).is_empty());
+    }
 }
@@ -0,0 +1,632 @@
+/// Software model of the board
+///
+/// Emulates 1986VE9x ROM UART boot loader and RAM boot loader from
+/// firmware/1986_BOOT_UART.hex on memory map of the selected chip, so the
+/// whole upload procedure can be run without real hardware. Uploaded RAM
+/// code is never executed, after 'R' emulator just switches to RAM boot
+/// loader command set.
+///
+/// Like the real RAM boot loader 'V' reads flash only: flash controller
+/// fills 256 byte buffer starting at 'A' address, which is returned by 8
+/// byte blocks. Controller sees nothing but flash array, addresses out of
+/// flash wrap into it.
+///
+/// RAM boot loader also understands extension commands from PROTOCOL.md,
+/// which need patched loader on real board, unless it is started as stock
+/// loader ignoring them.
+///
+use std::{
+    io::{ Read, Write },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    baud,
+    com_port::{
+        self,
+        PipePort,
+        Transport,
+    },
+    target::{
+        Chip,
+        Region,
+    },
+};
+
+const PROMPT: [u8; 3] = [0xD, 0xA, 0x3E];
+const ID: &[u8; 12] = b"1986BOOTUART";
+
+/// Cortex-M3 r2p1 core of 1986VE9x
+const CPUID: u32 = 0x412FC231;
+const CPUID_ADDR: u32 = 0xE000ED00;
+
+#[derive(PartialEq)]
+enum Mode {
+    /// ROM boot loader waiting for sync
+    Reset,
+    /// ROM boot loader
+    Rom,
+    /// RAM boot loader uploaded with 'L' and started with 'R'
+    Loader,
+    /// User program started, nothing is answered anymore
+    Running,
+}
+
+pub struct Emulator {
+    port: PipePort,
+    mode: Mode,
+    flash_region: &'static Region,
+    ram_region: &'static Region,
+    page_size: u32,
+    flash: Vec<u8>,
+    ram: Vec<u8>,
+    /// RAM boot loader current flash address
+    addr: u32,
+    /// Flash read buffer of 'V' command
+    window: Vec<u8>,
+    /// Position of the next 'V' block in the read buffer, buffer is
+    /// refilled at 0
+    window_pos: usize,
+    /// Answer extension commands, stock loader ignores them
+    extensions: bool,
+}
+
+/// Start emulator in background thread
+///
+/// Returns port connected to emulated board. Emulator stops as soon as
+/// the port is dropped. Without `extensions` RAM boot loader behaves like
+/// stock one and ignores extension commands.
+///
+pub fn spawn(chip: &'static Chip, extensions: bool) -> PipePort {
+    let (host, board) = com_port::pipe();
+
+    thread::spawn(move || {
+        if let Err(err) = Emulator::new(board, chip, extensions).run() {
+            debug!("Emulator stopped: {}", err);
+        }
+    });
+
+    return host;
+}
+
+impl Emulator {
+    pub fn new(port: PipePort, chip: &'static Chip, extensions: bool) -> Emulator {
+        let flash_region = chip.memory.flash();
+        let ram_region = chip.memory.ram();
+
+        Emulator {
+            port,
+            mode: Mode::Reset,
+            flash_region,
+            ram_region,
+            page_size: chip.page_size,
+            flash: vec![0xFF; flash_region.size as usize],
+            ram: vec![0x00; ram_region.size as usize],
+            addr: 0,
+            window: vec![],
+            window_pos: 0,
+            extensions,
+        }
+    }
+
+    /// Process commands until the other end of the pipe is closed
+    pub fn run(mut self) -> Result<(), com_port::Error> {
+        self.port.set_timeout(Duration::MAX)?;
+
+        loop {
+            let cmd = self.read_byte()?;
+            match self.mode {
+                Mode::Reset | Mode::Rom => self.rom_command(cmd)?,
+                Mode::Loader => self.loader_command(cmd)?,
+                Mode::Running => {},
+            }
+        }
+    }
+
+    /// ROM boot loader commands
+    fn rom_command(&mut self, cmd: u8) -> Result<(), com_port::Error> {
+        match cmd {
+            0x00 if self.mode == Mode::Reset => {
+                self.mode = Mode::Rom;
+                self.write(&PROMPT)?;
+            },
+            0x0D => self.write(&PROMPT)?,
+            b'B' => {
+                let baud_rate = self.read_u32()?;
+                debug!("Emulator: baud rate {}", baud_rate);
+                // answer goes at old speed
+                self.write(b"B")?;
+
+                // UART works at quite different speed if divider is too rough
+                let rate = match baud::divider(baud::HSI_CLOCK, baud_rate) {
+                    Some(div) if div.error.abs() <= baud::MAX_ERROR => baud_rate,
+                    Some(div) => div.actual,
+                    None => 0,
+                };
+                self.port.set_baud_rate(rate)?;
+            },
+            b'L' => {
+                let addr = self.read_u32()?;
+                let size = self.read_u32()?;
+                debug!("Emulator: load 0x{:0>8X?} {} bytes", addr, size);
+                if !self.ram_region.contains(addr, size) {
+                    return self.write(b"E");
+                }
+
+                self.write(b"L")?;
+                let data = self.read(size as usize)?;
+                let offset = (addr - self.ram_region.start) as usize;
+                self.ram[offset..offset + data.len()].copy_from_slice(&data);
+                self.write(b"K")?;
+            },
+            b'Y' => {
+                let addr = self.read_u32()?;
+                let size = self.read_u32()?;
+                if !self.ram_region.contains(addr, size) {
+                    return self.write(b"E");
+                }
+
+                let offset = (addr - self.ram_region.start) as usize;
+                let mut resp = vec![b'Y'];
+                resp.extend_from_slice(&self.ram[offset..offset + size as usize]);
+                resp.push(b'K');
+                self.write(&resp)?;
+            },
+            b'R' => {
+                let addr = self.read_u32()?;
+                debug!("Emulator: run 0x{:0>8X?}", addr);
+                if !self.ram_region.contains(addr, 1) {
+                    return self.write(b"E");
+                }
+
+                self.write(b"R")?;
+                self.mode = Mode::Loader;
+            },
+            b'I' => self.write(ID)?,
+            _ => {},
+        }
+
+        return Ok(());
+    }
+
+    /// RAM boot loader commands
+    fn loader_command(&mut self, cmd: u8) -> Result<(), com_port::Error> {
+        match cmd {
+            b'A' => {
+                self.addr = self.read_u32()?;
+                let sum = self.addr.to_le_bytes().iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+                self.write(&[sum])?;
+                self.window_pos = 0;
+            },
+            b'P' => {
+                let data = self.read(256)?;
+                for (i, byte) in data.iter().enumerate() {
+                    if let Some(offset) = self.flash_offset(self.addr + i as u32) {
+                        // programming can only clear bits
+                        self.flash[offset] &= byte;
+                    }
+                }
+                self.addr += 256;
+                self.window_pos = 0;
+
+                let sum = data.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+                self.write(&[sum])?;
+            },
+            b'V' => {
+                if self.window_pos == 0 {
+                    self.window = (0..256).map(|i| self.read_flash(self.addr.wrapping_add(i))).collect();
+                }
+
+                let data = self.window[self.window_pos..self.window_pos + 8].to_vec();
+                self.window_pos += 8;
+                if self.window_pos == self.window.len() {
+                    self.window_pos = 0;
+                    self.addr = self.addr.wrapping_add(256);
+                }
+                self.write(&data)?;
+            },
+            b'E' => {
+                self.flash.iter_mut().for_each(|byte| *byte = 0xFF);
+
+                // answer with address of the first word not erased
+                let mut addr = self.flash_region.start;
+                let mut data = 0xFFFFFFFFu32;
+                for word in self.flash.chunks(4) {
+                    data = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+                    if data != 0xFFFFFFFF {
+                        break;
+                    }
+                    addr += 4;
+                }
+
+                let mut resp = vec![b'E'];
+                resp.extend_from_slice(&addr.to_le_bytes());
+                resp.extend_from_slice(&data.to_le_bytes());
+                self.write(&resp)?;
+            },
+            b'S' if self.extensions => {
+                self.write(b"S")?;
+                let addr = self.read_u32()?;
+                let Some(offset) = self.flash_offset(addr) else {
+                    return self.write(b"E");
+                };
+
+                let start = offset - offset % self.page_size as usize;
+                self.flash[start..start + self.page_size as usize].fill(0xFF);
+                self.write(b"K")?;
+            },
+            b'C' if self.extensions => {
+                self.write(b"C")?;
+                let addr = self.read_u32()?;
+                let len = self.read_u32()?;
+
+                let data = (0..len).map(|i| self.read_mem(addr.wrapping_add(i))).collect::<Vec<u8>>();
+                self.write(&crc32fast::hash(&data).to_le_bytes())?;
+            },
+            b'W' if self.extensions => {
+                self.write(b"W")?;
+                let addr = self.read_u32()?;
+
+                let data = (0..4).map(|i| self.read_mem(addr.wrapping_add(i))).collect::<Vec<u8>>();
+                self.write(&data)?;
+            },
+            b'I' => self.write(ID)?,
+            b'R' => {
+                self.write(b"R")?;
+                self.mode = Mode::Running;
+            },
+            b'J' if self.extensions => {
+                self.write(b"J")?;
+                let addr = self.read_u32()?;
+                debug!("Emulator: jump 0x{:0>8X?}", addr);
+                if !self.flash_region.contains(addr, 1) && !self.ram_region.contains(addr, 1) {
+                    return self.write(b"E");
+                }
+
+                self.write(b"K")?;
+                self.mode = Mode::Running;
+            },
+            _ => {},
+        }
+
+        return Ok(());
+    }
+
+    /// Flash byte as flash controller sees it
+    fn read_flash(&self, addr: u32) -> u8 {
+        let offset = addr.wrapping_sub(self.flash_region.start) % self.flash_region.size;
+        return self.flash[offset as usize];
+    }
+
+    /// Any memory byte, as extension commands running on the core see it
+    fn read_mem(&self, addr: u32) -> u8 {
+        if let Some(offset) = self.flash_offset(addr) {
+            return self.flash[offset];
+        }
+        if self.ram_region.contains(addr, 1) {
+            return self.ram[(addr - self.ram_region.start) as usize];
+        }
+        if (CPUID_ADDR..CPUID_ADDR + 4).contains(&addr) {
+            return CPUID.to_le_bytes()[(addr - CPUID_ADDR) as usize];
+        }
+
+        return 0;
+    }
+
+    fn flash_offset(&self, addr: u32) -> Option<usize> {
+        if self.flash_region.contains(addr, 1) {
+            return Some((addr - self.flash_region.start) as usize);
+        }
+
+        return None;
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>, com_port::Error> {
+        let mut buf = vec![0; len];
+        self.port.read_exact(&mut buf)?;
+        return Ok(buf);
+    }
+
+    fn read_byte(&mut self) -> Result<u8, com_port::Error> {
+        return Ok(self.read(1)?[0]);
+    }
+
+    fn read_u32(&mut self) -> Result<u32, com_port::Error> {
+        let buf = self.read(4)?;
+        return Ok(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]));
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), com_port::Error> {
+        self.port.write_all(buf)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{ self, Error },
+        firmware::HexFile,
+        loader,
+        target,
+    };
+
+    fn chip() -> &'static Chip {
+        return target::find_chip(target::DEFAULT_CHIP).unwrap();
+    }
+
+    /// Start emulator and upload RAM boot loader to it
+    fn connect(extensions: bool) -> PipePort {
+        let mut port = spawn(chip(), extensions);
+
+        command::check_port(&mut port).unwrap();
+        let boot_loader = loader::read_loader(None, chip()).unwrap();
+        command::boot_load(&mut port, boot_loader).unwrap();
+        command::read_info(&mut port).unwrap();
+
+        return port;
+    }
+
+    /// Sparse image with unaligned segments and a gap of several chunks
+    fn image() -> HexFile {
+        let mut image = HexFile::new();
+        image.insert(0x08000010, &(0..100).collect::<Vec<u8>>()).unwrap();
+        image.insert(0x08001234, b"milandr").unwrap();
+        return image;
+    }
+
+    #[test]
+    fn flash_round_trip() {
+        let mut port = connect(true);
+        let image = image();
+
+        command::erase(&mut port, chip()).unwrap();
+        command::program(&mut port, &image, 0xFF, 3, true).unwrap();
+        command::verify(&mut port, &image, false).unwrap();
+        command::verify_crc(&mut port, &image, chip().page_size, false).unwrap();
+
+        let buf = command::read_flash(&mut port, 0x08001230, 16).unwrap();
+        assert_eq!(&buf[..4], &[0xFF; 4]);
+        assert_eq!(&buf[4..11], b"milandr");
+
+        // several read buffers from unaligned address
+        let buf = command::read_flash(&mut port, 0x08000008, 0x300).unwrap();
+        assert_eq!(&buf[..8], &[0xFF; 8]);
+        assert_eq!(buf[8..108], (0..100).collect::<Vec<u8>>()[..]);
+        assert!(buf[108..].iter().all(|&byte| byte == 0xFF));
+    }
+
+    #[test]
+    fn verify_reports_first_mismatch() {
+        let mut port = connect(true);
+
+        command::erase(&mut port, chip()).unwrap();
+        command::program(&mut port, &image(), 0xFF, 3, true).unwrap();
+
+        let mut other = HexFile::new();
+        other.insert(0x08001234, b"milcup").unwrap();
+        match command::verify_crc(&mut port, &other, chip().page_size, false) {
+            Err(Error::Verify(mismatch)) => {
+                assert_eq!(mismatch.addr, 0x08001237);
+                assert_eq!(mismatch.expected, b'c');
+                assert_eq!(mismatch.actual, b'a');
+            },
+            res => panic!("Verify error expected, got {:?}", res),
+        }
+
+        match command::verify(&mut port, &other, true) {
+            Err(Error::Mismatches(list)) => assert_eq!(list.len(), 3),
+            res => panic!("Mismatches expected, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn page_erase_keeps_other_pages() {
+        let mut port = connect(true);
+        let image = image();
+
+        command::erase(&mut port, chip()).unwrap();
+        command::program(&mut port, &image, 0xFF, 3, true).unwrap();
+        command::erase_page(&mut port, 0x08001000).unwrap();
+
+        let flash = chip().memory.flash();
+        let ranges = command::blank_check(&mut port, flash.start, flash.size).unwrap();
+        assert_eq!(ranges, vec![0x08000010..0x08000010 + 100]);
+    }
+
+    #[test]
+    fn read_cpuid() {
+        let mut port = connect(true);
+        assert_eq!(command::read_cpuid(&mut port).unwrap(), CPUID);
+    }
+
+    #[test]
+    fn stock_loader_ignores_extensions() {
+        let mut port = connect(false);
+        let image = image();
+
+        assert!(matches!(command::erase_page(&mut port, 0x08000000), Err(Error::Unsupported('S'))));
+        assert!(matches!(command::read_crc(&mut port, 0x08000000, 16), Err(Error::Unsupported('C'))));
+        assert!(matches!(command::run_at(&mut port, 0x08000101), Err(Error::Unsupported('J'))));
+        assert!(matches!(command::read_cpuid(&mut port), Err(Error::Unsupported('W'))));
+
+        // loader is still in command mode
+        command::read_info(&mut port).unwrap();
+        command::erase(&mut port, chip()).unwrap();
+        command::program(&mut port, &image, 0xFF, 3, true).unwrap();
+
+        // falls back to readback
+        command::verify_crc(&mut port, &image, chip().page_size, false).unwrap();
+        command::run(&mut port).unwrap();
+    }
+
+    /// Port of adapter which can't work faster than 230400
+    struct SlowPort(PipePort);
+
+    impl Read for SlowPort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            return self.0.read(buf);
+        }
+    }
+
+    impl Write for SlowPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            return self.0.write(buf);
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return self.0.flush();
+        }
+    }
+
+    impl Transport for SlowPort {
+        fn timeout(&self) -> Duration {
+            return self.0.timeout();
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> Result<(), com_port::Error> {
+            return self.0.set_timeout(timeout);
+        }
+
+        fn baud_rate(&self) -> Result<u32, com_port::Error> {
+            return self.0.baud_rate();
+        }
+
+        fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), com_port::Error> {
+            if baud_rate > 230400 {
+                return Err(std::io::Error::other("Baud rate is not supported").into());
+            }
+            return self.0.set_baud_rate(baud_rate);
+        }
+    }
+
+    #[test]
+    fn negotiation_skips_rates_port_rejects() {
+        let mut port = SlowPort(spawn(chip(), true));
+        command::check_port(&mut port).unwrap();
+
+        let rate = command::negotiate_baud_rate(&mut port, &[460800, 230400, 115200]).unwrap();
+        assert_eq!(rate, 230400);
+        assert_eq!(port.baud_rate().unwrap(), 230400);
+        command::read_baud_rate(&mut port).unwrap();
+    }
+
+    /// Line fault hitting the first 'P' command
+    enum Fault {
+        /// Checksum answer is lost
+        DropChecksum,
+        /// Payload byte at given position is received as another value
+        Corrupt(usize, u8),
+    }
+
+    /// Port injecting a single fault into programming
+    struct FaultyPort {
+        port: PipePort,
+        fault: Option<Fault>,
+        /// Last write was 'P' command, the next one is its payload
+        program: bool,
+        /// Answer byte to throw away
+        drop: bool,
+    }
+
+    impl FaultyPort {
+        fn new(port: PipePort, fault: Fault) -> FaultyPort {
+            return FaultyPort { port, fault: Some(fault), program: false, drop: false };
+        }
+    }
+
+    impl Read for FaultyPort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.drop {
+                self.drop = false;
+                self.port.read_exact(&mut [0u8; 1])?;
+            }
+            return self.port.read(buf);
+        }
+    }
+
+    impl Write for FaultyPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut buf = buf.to_vec();
+            if self.program && buf.len() == 256 {
+                match self.fault.take() {
+                    Some(Fault::DropChecksum) => self.drop = true,
+                    Some(Fault::Corrupt(pos, byte)) => buf[pos] = byte,
+                    None => {},
+                }
+            }
+            self.program = buf == b"P";
+
+            return self.port.write(&buf);
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return self.port.flush();
+        }
+    }
+
+    impl Transport for FaultyPort {
+        fn timeout(&self) -> Duration {
+            return self.port.timeout();
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> Result<(), com_port::Error> {
+            return self.port.set_timeout(timeout);
+        }
+
+        fn baud_rate(&self) -> Result<u32, com_port::Error> {
+            return self.port.baud_rate();
+        }
+
+        fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), com_port::Error> {
+            return self.port.set_baud_rate(baud_rate);
+        }
+    }
+
+    /// Connect and erase flash, every fault is injected after that
+    fn faulty_port(fault: Fault) -> FaultyPort {
+        let mut port = connect(true);
+        command::erase(&mut port, chip()).unwrap();
+        port.set_timeout(Duration::from_millis(300)).unwrap();
+
+        return FaultyPort::new(port, fault);
+    }
+
+    #[test]
+    fn lost_checksum_is_retried() {
+        let mut port = faulty_port(Fault::DropChecksum);
+        let image = image();
+
+        command::program(&mut port, &image, 0xFF, 3, true).unwrap();
+        assert!(port.fault.is_none());
+        command::verify(&mut port, &image, false).unwrap();
+    }
+
+    #[test]
+    fn retry_fixes_bits_left_set() {
+        // 0x08000015 holds 5, received 0xFF keeps the bits to clear on retry
+        let mut port = faulty_port(Fault::Corrupt(0x15, 0xFF));
+        let image = image();
+
+        command::program(&mut port, &image, 0xFF, 3, true).unwrap();
+        assert!(port.fault.is_none());
+        command::verify(&mut port, &image, false).unwrap();
+    }
+
+    #[test]
+    fn retry_over_burned_bits_fails() {
+        // received 0x00 clears bits of 5 for good
+        let mut port = faulty_port(Fault::Corrupt(0x15, 0x00));
+
+        match command::program(&mut port, &image(), 0xFF, 3, true) {
+            Err(Error::Verify(mismatch)) => {
+                assert_eq!(mismatch.addr, 0x08000015);
+                assert_eq!(mismatch.expected, 5);
+                assert_eq!(mismatch.actual, 0);
+            },
+            res => panic!("Verify error expected, got {:?}", res),
+        }
+    }
+}
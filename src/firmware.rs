@@ -1,32 +1,98 @@
 use std::{
+    convert::TryFrom,
     fs,
     fmt,
     io,
     path::Path,
+    collections::BTreeMap,
+};
+
+use goblin::elf::{
+    Elf,
+    program_header::PT_LOAD,
+    section_header::{ SHF_ALLOC, SHT_NOBITS },
 };
 
 use ihex::{
     reader::*,
     record::Record,
+    writer::{
+        self,
+        WriterError,
+    },
 };
 
-pub struct HexFile {
+/// Continuous piece of firmware data placed at given address
+pub struct Segment {
     pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// Address right after the last byte of segment
+    ///
+    /// `HexFile::insert` never takes data the end of which does not fit
+    /// into 32 bits, so it can't overflow for image segments
+    pub fn end(&self) -> u32 {
+        self.addr + self.data.len() as u32
+    }
+}
+
+/// Page aligned piece of memory ready to be sent to the board
+///
+/// Unlike `Segment` block always starts at page boundary and its length
+/// is a multiple of page size, holes are filled with padding
+pub struct Block {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+/// Section of ELF file, kept for information only
+pub struct Section {
+    pub name: String,
+    /// Address section works at
+    pub addr: u32,
+    /// Address section is loaded to, None when it is not loaded at all
+    pub load_addr: Option<u32>,
     pub size: u32,
-    pub buf: Vec<u8>
+}
+
+/// Sparse memory image
+///
+/// Image is a list of address tagged segments sorted by address. Segments
+/// never overlap, adjacent ones are merged together.
+#[derive(Default)]
+pub struct HexFile {
+    pub segments: Vec<Segment>,
+    /// Sections of ELF file image was made from
+    pub sections: Vec<Section>,
+    /// Program entry point if file has one
+    pub start: Option<u32>,
 }
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
+    Hex(ReaderError),
+    HexWrite(WriterError),
+    Srec { line: usize, reason: &'static str },
+    Elf(goblin::error::Error),
+    Overlap(u32),
+    /// Data starting at the address runs past the end of 32 bit address space
+    AddressOverflow(u32),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Io(ref err) => err.fmt(f),
-            // CliError::NotFound => write!(f, "No matching cities with a \
-            //                                  population were found."),
+            Error::Hex(ref err) => write!(f, "Intel HEX error: {}", err),
+            Error::HexWrite(ref err) => write!(f, "Intel HEX error: {}", err),
+            Error::Srec { line, reason } => write!(f, "S-record error at line {}: {}", line, reason),
+            Error::Elf(ref err) => write!(f, "ELF error: {}", err),
+            Error::Overlap(addr) => write!(f, "Data overlap at address 0x{:0>8X?}", addr),
+            Error::AddressOverflow(addr) =>
+                write!(f, "Data at address 0x{:0>8X?} runs past end of address space", addr),
         }
     }
 }
@@ -37,111 +103,679 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<ReaderError> for Error {
+    fn from(err: ReaderError) -> Error {
+        Error::Hex(err)
+    }
+}
+
+impl From<WriterError> for Error {
+    fn from(err: WriterError) -> Error {
+        Error::HexWrite(err)
+    }
+}
+
+impl From<goblin::error::Error> for Error {
+    fn from(err: goblin::error::Error) -> Error {
+        Error::Elf(err)
+    }
+}
+
 impl From<Error> for std::io::Error {
     fn from(_err : Error) -> std::io::Error {
-        return std::io::Error::new(std::io::ErrorKind::Other, "Firmware Error");
+        std::io::Error::other("Firmware Error")
     }
 }
 
+impl HexFile {
+    pub fn new() -> HexFile {
+        HexFile { segments: Vec::new(), sections: Vec::new(), start: None }
+    }
+
+    /// Lowest address of image data
+    pub fn addr(&self) -> u32 {
+        return self.segments.first().map_or(0, |seg| seg.addr);
+    }
+
+    /// Address right after the highest byte of image data
+    pub fn end(&self) -> u32 {
+        return self.segments.last().map_or(0, |seg| seg.end());
+    }
+
+    /// Number of data bytes in image, gaps are not counted
+    pub fn size(&self) -> u32 {
+        return self.segments.iter().map(|seg| seg.data.len() as u32).sum();
+    }
+
+    /// Check if byte at given address belongs to image data
+    pub fn contains(&self, addr: u32) -> bool {
+        return self.segments.iter().any(|seg| addr >= seg.addr && addr < seg.end());
+    }
+
+    /// Put data to the image at given address
+    ///
+    /// Data touching an existing segment is merged into it, overlapping
+    /// data and data running past the end of address space are errors.
+    pub fn insert(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let end = u32::try_from(data.len()).ok()
+            .and_then(|len| addr.checked_add(len))
+            .ok_or(Error::AddressOverflow(addr))?;
+        let pos = self.segments.partition_point(|seg| seg.addr < addr);
+
+        if pos > 0 && self.segments[pos - 1].end() > addr {
+            return Err(Error::Overlap(addr));
+        }
+        if pos < self.segments.len() && self.segments[pos].addr < end {
+            return Err(Error::Overlap(self.segments[pos].addr));
+        }
+
+        if pos > 0 && self.segments[pos - 1].end() == addr {
+            self.segments[pos - 1].data.extend_from_slice(data);
+        } else {
+            self.segments.insert(pos, Segment { addr, data: data.to_vec() });
+        }
+
+        // join with the next segment if the gap is closed now
+        let prev = if pos > 0 && self.segments[pos - 1].end() == end { pos - 1 } else { pos };
+        if prev + 1 < self.segments.len() && self.segments[prev].end() == self.segments[prev + 1].addr {
+            let next = self.segments.remove(prev + 1);
+            self.segments[prev].data.extend(next.data);
+        }
+
+        return Ok(());
+    }
+
+    /// Whole image as a single continuous buffer
+    ///
+    /// Gaps between segments are filled with `fill` value
+    pub fn contiguous(&self, fill: u8) -> Segment {
+        let base = self.addr();
+        let mut data = vec![fill; (self.end() - base) as usize];
+        for seg in &self.segments {
+            let offset = (seg.addr - base) as usize;
+            data[offset..offset + seg.data.len()].copy_from_slice(&seg.data);
+        }
+
+        return Segment { addr: base, data };
+    }
+
+    /// Same image with gaps between segments filled with `fill` value
+    pub fn fill_gaps(self, fill: u8) -> HexFile {
+        if self.segments.len() < 2 {
+            return self;
+        }
+
+        let segments = vec![self.contiguous(fill)];
+        return HexFile { segments, ..self };
+    }
+
+    /// Split image into page aligned blocks
+    ///
+    /// Every block is a run of consecutive `page_size` pages containing at
+    /// least one byte of image data. Bytes of the page not covered by image
+    /// are filled with `fill` value.
+    pub fn blocks(&self, page_size: u32, fill: u8) -> Vec<Block> {
+        let mut pages: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+
+        for seg in &self.segments {
+            for (i, byte) in seg.data.iter().enumerate() {
+                let addr = seg.addr + i as u32;
+                let page = addr - addr % page_size;
+                let buf = pages.entry(page).or_insert_with(|| vec![fill; page_size as usize]);
+                buf[(addr - page) as usize] = *byte;
+            }
+        }
+
+        let mut blocks: Vec<Block> = Vec::new();
+        for (page, mut buf) in pages {
+            match blocks.last_mut() {
+                Some(block) if block.addr + block.data.len() as u32 == page => block.data.append(&mut buf),
+                _ => blocks.push(Block { addr: page, data: buf }),
+            }
+        }
+
+        return blocks;
+    }
+
+    /// Print image layout
+    pub fn print_summary(&self) {
+        for sec in &self.sections {
+            match sec.load_addr {
+                Some(load_addr) => println!("    {:<16} 0x{:0>8X?} load 0x{:0>8X?} {} bytes", sec.name, sec.addr, load_addr, sec.size),
+                None => println!("    {:<16} 0x{:0>8X?} not loaded  {} bytes", sec.name, sec.addr, sec.size),
+            }
+        }
+        for seg in &self.segments {
+            println!("      Segment: 0x{:0>8X?}..0x{:0>8X?} {} bytes", seg.addr, seg.end(), seg.data.len());
+        }
+        println!("         Size: {} bytes", self.size());
+        if let Some(start) = self.start {
+            println!("        Start: 0x{:0>8X?}", start);
+        }
+    }
+}
+
+/// Read firmware file of any supported format
+///
+/// Format is chosen by file extension, unknown extensions are detected
+/// by content: ELF starts with 0x7F 'E' 'L' 'F', Intel HEX is a printable
+/// text starting with ':' and Motorola S-record is one starting with 'S'.
+/// Anything else is read as raw binary, so flash dump saved with any name
+/// can be written back.
+///
+/// Raw binary file has no address information so it is placed at `base_addr`
+///
+pub fn read_firmware_file(filename: &Path, base_addr: u32) -> Result<HexFile, Error> {
+    let ext = filename.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    return match ext.as_str() {
+        "hex" | "ihex" | "ihx" => read_hex_file(filename),
+        "srec" | "s19" | "s28" | "s37" | "mot" => read_srec_file(filename),
+        "elf" | "axf" | "out" => read_elf_file(filename),
+        "bin" => read_bin_file(filename, base_addr),
+        _ => {
+            let data = fs::read(filename)?;
+            if data.starts_with(b"\x7FELF") {
+                return parse_elf_buffer(&data);
+            }
+
+            let is_text = data.iter().all(|&byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace());
+            let text = String::from_utf8_lossy(&data);
+            match text.trim_start().chars().next() {
+                Some(':') if is_text => parse_hex_buffer(&text),
+                Some('S') if is_text => parse_srec_buffer(&text),
+                _ => {
+                    let mut hex_file = HexFile::new();
+                    hex_file.insert(base_addr, &data)?;
+                    Ok(hex_file)
+                },
+            }
+        },
+    };
+}
+
 pub fn read_hex_file(filename: &Path) -> Result<HexFile, Error> {
-    let data = fs::read_to_string(filename).expect("no such file");
+    let data = fs::read_to_string(filename)?;
     let firmware = parse_hex_buffer(&data)?;
     return Ok(firmware);
 }
 
+/// Parse Intel HEX text into memory image
+///
+/// Absolute address of data record is computed as
+/// Extended Linear Address + Extended Segment Address + record offset
+///
 pub fn parse_hex_buffer(data: &str) -> Result<HexFile, Error> {
-    // check addresses
-    let mut reader = Reader::new(data);
-    let base_addr : u16 = reader.fold(0, |curr_addr, rec| 
-        match rec {
-            Ok(Record::ExtendedLinearAddress(addr)) => curr_addr + addr,
-            Ok(Record::ExtendedSegmentAddress(addr)) => curr_addr + addr,
-            _ => curr_addr,
-        }
-    );
-
-    // file base addr
-    reader = Reader::new(data);
-    let data_addr : u16 = reader.filter(|rec| 
-        match rec {
-            Ok(Record::Data {offset: _, value: _}) => true,
-            _ => false
-        }
-    ).take(1).map(|rec| 
-        match rec {
-            Ok(Record::Data {offset, value: _}) => offset,
-            _ => 0
-        }
-    ).last().unwrap();
-
-    // file max addr
-    reader = Reader::new(data);
-    let data_size : u32 = reader.fold(0u32, |curr_len, rec| 
-        match rec {
-            Ok(Record::Data {offset: _, value}) => (value.len() as u32) + curr_len,
-            _ => curr_len,
-        }
-    );
-
-    // println!("   Max addr: 0x{:0>6X?}", file_max_offset);
-    // println!("           0x08000000");
-    // println!("           0x20000000");
-
-    // if file_data_len > max_size {
-    //     return Err("Oversize".to_string());
-    // }
-    //
-    // data
-    reader = Reader::new(data);
-    let file_data = reader.fold(Vec::<u8>::new(), |mut data, rec| 
-        match rec {
-            Ok(Record::Data {offset: _, mut value}) => {
-                data.append(&mut value);
-                data
+    let mut hex_file = HexFile::new();
+
+    let mut linear_addr : u32 = 0;
+    let mut segment_addr : u32 = 0;
+
+    for rec in Reader::new(data) {
+        match rec? {
+            Record::Data {offset, value} => {
+                debug!("Data at offset: 0x{:X?} len: {}", offset, value.len());
+                let addr = linear_addr.checked_add(segment_addr)
+                    .and_then(|addr| addr.checked_add(offset as u32))
+                    .ok_or(Error::AddressOverflow(linear_addr))?;
+                hex_file.insert(addr, &value)?;
             },
-            _ => data,
-        }
-    );
-
-    reader = Reader::new(&data);
-    let _x = reader.map(|r| 
-        match r {
-            Ok(Record::Data {offset, value})         => debug!("Data at offset: 0x{:X?} len: {}", offset, value.len()),
-            Ok(Record::ExtendedLinearAddress(addr))  => debug!("Extended Linear address: 0x{:X?}", addr),
-            Ok(Record::StartLinearAddress(addr))     => debug!("Start Linear address: {}", addr),
-            Ok(Record::ExtendedSegmentAddress(addr)) => debug!("Extended segment address: 0x{:X?}", addr),
-            Ok(Record::StartSegmentAddress {cs, ip}) => debug!("Start segment address: {} {}", cs, ip),
-            Ok(Record::EndOfFile)                    => debug!("END"),
-            // Ok(_) => eprintln!("Unknown record"),
-            Err(err) => error!("{}", err)
-        }
-    );
-
-    // println!("Reader count: {}", x.count());
-
-    // let lines = buf
-    //     .lines()
-    //     // .map(|str| Record::from_record_string(str))
-    //     // .collect();
-    //     .collect::<Vec<Result<Record>>>();
-    //
-    // println!("Lines: {}", lines.join("\n"));
-
-    let base_addr_buf = base_addr.to_be_bytes();
-    let data_addr_buf = data_addr.to_be_bytes();
-    let addr_buf : [u8; 4] = [base_addr_buf[0], base_addr_buf[1], data_addr_buf[0], data_addr_buf[1]];
-
-    let hex_file = HexFile {
-        addr: u32::from_be_bytes(addr_buf),
-        size: data_size,
-        buf: file_data,
-    };
+            Record::ExtendedLinearAddress(addr) => {
+                debug!("Extended Linear address: 0x{:X?}", addr);
+                linear_addr = (addr as u32) << 16;
+            },
+            Record::ExtendedSegmentAddress(addr) => {
+                debug!("Extended segment address: 0x{:X?}", addr);
+                segment_addr = (addr as u32) << 4;
+            },
+            Record::StartLinearAddress(addr)     => {
+                debug!("Start Linear address: 0x{:X?}", addr);
+                hex_file.start = Some(addr);
+            },
+            Record::StartSegmentAddress {cs, ip} => debug!("Start segment address: {} {}", cs, ip),
+            Record::EndOfFile                    => debug!("END"),
+        }
+    }
+
+    return Ok(hex_file);
+}
+
+pub fn read_srec_file(filename: &Path) -> Result<HexFile, Error> {
+    let data = fs::read_to_string(filename)?;
+    let firmware = parse_srec_buffer(&data)?;
+    return Ok(firmware);
+}
+
+/// Parse Motorola S-record text into memory image
+///
+/// S1, S2 and S3 data records carry 16, 24 and 32 bit addresses, header,
+/// count and start address records are checked but ignored
+///
+pub fn parse_srec_buffer(data: &str) -> Result<HexFile, Error> {
+    let mut hex_file = HexFile::new();
+
+    for (num, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let err = |reason| Error::Srec { line: num + 1, reason };
+
+        let mut chars = line.chars();
+        if chars.next() != Some('S') {
+            return Err(err("record must start with 'S'"));
+        }
+        let kind = chars.next().ok_or(err("no record type"))?;
+        let addr_len = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(err("unknown record type")),
+        };
+
+        let hex = &line[2..];
+        if !hex.is_ascii() || hex.len() % 2 != 0 {
+            return Err(err("odd number of hex digits"));
+        }
+        let bytes = (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| err("wrong hex digit"))?;
+
+        // byte count covers address, data and checksum
+        if bytes.is_empty() || bytes[0] as usize != bytes.len() - 1 {
+            return Err(err("wrong byte count"));
+        }
+
+        let sum = bytes[..bytes.len() - 1].iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+        if !sum != bytes[bytes.len() - 1] {
+            return Err(err("checksum mismatch"));
+        }
+
+        let body = &bytes[1..bytes.len() - 1];
+        if body.len() < addr_len {
+            return Err(err("record too short"));
+        }
+        let addr = body[..addr_len].iter().fold(0u32, |acc, &x| (acc << 8) | x as u32);
+        let value = &body[addr_len..];
 
-    println!("    Base addr: 0x{:0>8X?}", base_addr);
-    println!("    Data addr: 0x{:0>8X?}", data_addr);
-    println!("    Load addr: 0x{:0>8X?}", hex_file.addr);
-    println!("         Size: {} bytes", data_size);
+        match kind {
+            '1' | '2' | '3' => {
+                debug!("Data at 0x{:0>8X?} len: {}", addr, value.len());
+                hex_file.insert(addr, value)?;
+            },
+            '0' => debug!("Header: {}", String::from_utf8_lossy(value)),
+            '5' | '6' => debug!("Record count: {}", addr),
+            _ => {
+                debug!("Start address: 0x{:0>8X?}", addr);
+                // zero is written when there is no entry point, Thumb code never starts there
+                hex_file.start = Some(addr).filter(|&addr| addr != 0);
+            },
+        }
+    }
 
     return Ok(hex_file);
 }
 
+/// Start of Cortex-M SRAM region
+const SRAM_START: u64 = 0x20000000;
+
+/// End of Cortex-M SRAM region
+const SRAM_END: u64 = 0x40000000;
+
+pub fn read_elf_file(filename: &Path) -> Result<HexFile, Error> {
+    let data = fs::read(filename)?;
+    let firmware = parse_elf_buffer(&data)?;
+    return Ok(firmware);
+}
+
+/// Parse ELF executable into memory image
+///
+/// Image is built from PT_LOAD program segments placed at their physical
+/// (load) addresses. Segments with no file data (.bss, NOLOAD) and
+/// segments loaded to SRAM are skipped as there is nothing to flash.
+///
+pub fn parse_elf_buffer(data: &[u8]) -> Result<HexFile, Error> {
+    let elf = Elf::parse(data)?;
+    let mut hex_file = HexFile::new();
+
+    let is_ram = |addr: u64| (SRAM_START..SRAM_END).contains(&addr);
+
+    for ph in elf.program_headers.iter().filter(|ph| ph.p_type == PT_LOAD) {
+        debug!("Segment vaddr 0x{:0>8X?} paddr 0x{:0>8X?} file size {} mem size {}",
+            ph.p_vaddr, ph.p_paddr, ph.p_filesz, ph.p_memsz);
+
+        if ph.p_filesz == 0 || is_ram(ph.p_paddr) {
+            continue;
+        }
+
+        let addr = elf_address(ph.p_paddr)?;
+        let value = usize::try_from(ph.p_offset).ok()
+            .zip(usize::try_from(ph.p_filesz).ok())
+            .and_then(|(start, size)| start.checked_add(size).map(|end| start..end))
+            .and_then(|range| data.get(range))
+            .ok_or(Error::Elf(goblin::error::Error::Malformed(
+                format!("Segment 0x{:0>8X?} is out of file", ph.p_paddr))))?;
+
+        hex_file.insert(addr, value)?;
+    }
+
+    hex_file.start = Some(elf_address(elf.entry)?).filter(|&addr| addr != 0);
+
+    // section summary
+    for sh in elf.section_headers.iter().filter(|sh| sh.sh_flags & SHF_ALLOC as u64 != 0 && sh.sh_size > 0) {
+        let name = elf.shdr_strtab.get_at(sh.sh_name).unwrap_or("?").to_string();
+
+        // load address comes from the segment containing section data
+        let load_addr = if sh.sh_type == SHT_NOBITS {
+            None
+        } else {
+            elf.program_headers.iter()
+                .filter(|ph| ph.p_type == PT_LOAD && ph.p_filesz > 0)
+                .find(|ph| sh.sh_offset >= ph.p_offset
+                    && sh.sh_offset.saturating_add(sh.sh_size) <= ph.p_offset.saturating_add(ph.p_filesz))
+                .and_then(|ph| ph.p_paddr.checked_add(sh.sh_offset - ph.p_offset))
+                .filter(|&addr| !is_ram(addr))
+                .and_then(|addr| u32::try_from(addr).ok())
+        };
+
+        hex_file.sections.push(Section { name, addr: sh.sh_addr as u32, load_addr, size: sh.sh_size as u32 });
+    }
+
+    return Ok(hex_file);
+}
+
+/// ELF address checked to fit into 32 bit address space of the chip
+fn elf_address(addr: u64) -> Result<u32, Error> {
+    return u32::try_from(addr).map_err(|_| Error::Elf(goblin::error::Error::Malformed(
+        format!("Address 0x{:X?} is out of 32 bit address space", addr))));
+}
+
+/// Read raw binary file placing it at given address
+pub fn read_bin_file(filename: &Path, base_addr: u32) -> Result<HexFile, Error> {
+    let data = fs::read(filename)?;
+
+    let mut hex_file = HexFile::new();
+    hex_file.insert(base_addr, &data)?;
+
+    return Ok(hex_file);
+}
+
+/// Save memory image as Intel HEX file
+pub fn write_hex_file(filename: &Path, data: &HexFile) -> Result<(), Error> {
+    let mut text = writer::create_object_file_representation(&hex_records(data))?;
+    text.push('\n');
+    fs::write(filename, text)?;
+    return Ok(());
+}
+
+/// Save memory image as raw binary file
+///
+/// Gaps between segments are filled with 0xFF
+pub fn write_bin_file(filename: &Path, data: &HexFile) -> Result<(), Error> {
+    fs::write(filename, data.contiguous(0xFF).data)?;
+    return Ok(());
+}
+
+/// Convert memory image into list of Intel HEX records
+///
+/// Data records are up to 16 bytes long and never cross 64K boundary so
+/// Extended Linear Address record is emitted only when high part of the
+/// address changes
+fn hex_records(data: &HexFile) -> Vec<Record> {
+    let mut records = Vec::new();
+    let mut linear_addr : Option<u16> = None;
+
+    for seg in &data.segments {
+        let mut addr = seg.addr;
+        let mut rest = seg.data.as_slice();
+
+        while !rest.is_empty() {
+            let high = (addr >> 16) as u16;
+            if linear_addr != Some(high) {
+                records.push(Record::ExtendedLinearAddress(high));
+                linear_addr = Some(high);
+            }
+
+            let boundary = 0x10000 - (addr & 0xFFFF);
+            let len = rest.len().min(16).min(boundary as usize);
+            records.push(Record::Data { offset: addr as u16, value: rest[..len].to_vec() });
+
+            addr += len as u32;
+            rest = &rest[len..];
+        }
+    }
+    if let Some(start) = data.start {
+        records.push(Record::StartLinearAddress(start));
+    }
+    records.push(Record::EndOfFile);
+
+    return records;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(image: &HexFile) -> Vec<(u32, usize)> {
+        return image.segments.iter().map(|seg| (seg.addr, seg.data.len())).collect();
+    }
+
+    #[test]
+    fn insert_merges_neighbours() {
+        let mut image = HexFile::new();
+        image.insert(0x100, &[1; 16]).unwrap();
+        image.insert(0x200, &[2; 16]).unwrap();
+        image.insert(0x110, &[3; 16]).unwrap();
+        assert_eq!(segments(&image), vec![(0x100, 32), (0x200, 16)]);
+
+        // closing the gap joins all three
+        image.insert(0x120, &[4; 0xE0]).unwrap();
+        assert_eq!(segments(&image), vec![(0x100, 0x110)]);
+
+        image.insert(0xF0, &[5; 16]).unwrap();
+        assert_eq!(segments(&image), vec![(0xF0, 0x120)]);
+        assert_eq!(image.size(), 0x120);
+        assert_eq!(image.segments[0].data[0x10..0x12], [1, 1]);
+    }
+
+    #[test]
+    fn insert_rejects_overlap() {
+        let mut image = HexFile::new();
+        image.insert(0x100, &[1; 16]).unwrap();
+
+        assert!(matches!(image.insert(0x10F, &[2; 4]), Err(Error::Overlap(0x10F))));
+        assert!(matches!(image.insert(0xF8, &[2; 9]), Err(Error::Overlap(0x100))));
+        assert!(matches!(image.insert(0xFFFFFFF0, &[2; 17]), Err(Error::AddressOverflow(0xFFFFFFF0))));
+        assert_eq!(segments(&image), vec![(0x100, 16)]);
+    }
+
+    #[test]
+    fn fill_gaps_joins_segments() {
+        let mut image = HexFile::new();
+        image.insert(0x100, &[1; 4]).unwrap();
+        image.insert(0x108, &[2; 4]).unwrap();
+        image.start = Some(0x101);
+
+        let image = image.fill_gaps(0xAA);
+        assert_eq!(segments(&image), vec![(0x100, 12)]);
+        assert_eq!(image.segments[0].data, [1, 1, 1, 1, 0xAA, 0xAA, 0xAA, 0xAA, 2, 2, 2, 2]);
+        assert_eq!(image.start, Some(0x101));
+    }
+
+    #[test]
+    fn hex_records_split_at_64k() {
+        let mut image = HexFile::new();
+        image.insert(0x0800FFF8, &(0..24).collect::<Vec<u8>>()).unwrap();
+        image.start = Some(0x08000101);
+
+        let records = hex_records(&image);
+        assert_eq!(records, vec![
+            Record::ExtendedLinearAddress(0x0800),
+            Record::Data { offset: 0xFFF8, value: (0..8).collect() },
+            Record::ExtendedLinearAddress(0x0801),
+            Record::Data { offset: 0x0000, value: (8..24).collect() },
+            Record::StartLinearAddress(0x08000101),
+            Record::EndOfFile,
+        ]);
+
+        // written file reads back the same
+        let text = writer::create_object_file_representation(&records).unwrap();
+        let back = parse_hex_buffer(&text).unwrap();
+        assert_eq!(segments(&back), vec![(0x0800FFF8, 24)]);
+        assert_eq!(back.segments[0].data, image.segments[0].data);
+        assert_eq!(back.start, Some(0x08000101));
+    }
+
+    fn srec_error(data: &str) -> (usize, &'static str) {
+        match parse_srec_buffer(data) {
+            Err(Error::Srec { line, reason }) => return (line, reason),
+            Err(err) => panic!("S-record error expected, got {}", err),
+            Ok(_) => panic!("S-record error expected"),
+        }
+    }
+
+    #[test]
+    fn hex_address_overflow() {
+        // ELA 0xFFFF with ESA 0x1000 points past 4 GB
+        let data = ":02000004FFFFFC\n:020000021000EC\n:0100000000FF\n:00000001FF\n";
+        assert!(matches!(parse_hex_buffer(data), Err(Error::AddressOverflow(0xFFFF0000))));
+    }
+
+    #[test]
+    fn srec_address_widths() {
+        let image = parse_srec_buffer("S00600004844521B\n\
+            S1061000010203E3\n\
+            S2060810000405D8\n\
+            S3090800000006070809D0\n\
+            S5030003F9\n").unwrap();
+
+        let segments = image.segments.iter().map(|seg| (seg.addr, seg.data.clone())).collect::<Vec<_>>();
+        assert_eq!(segments, vec![
+            (0x00001000, vec![1, 2, 3]),
+            (0x00081000, vec![4, 5]),
+            (0x08000000, vec![6, 7, 8, 9]),
+        ]);
+        assert_eq!(image.start, None);
+    }
+
+    #[test]
+    fn srec_entry_point() {
+        assert_eq!(parse_srec_buffer("S70508000101F0\n").unwrap().start, Some(0x08000101));
+        assert_eq!(parse_srec_buffer("S804000101F9\n").unwrap().start, Some(0x00000101));
+        assert_eq!(parse_srec_buffer("S9030101FA\n").unwrap().start, Some(0x00000101));
+        // zero means there is no entry point
+        assert_eq!(parse_srec_buffer("S9030000FC\n").unwrap().start, None);
+    }
+
+    #[test]
+    fn srec_bad_records() {
+        assert_eq!(srec_error("S1061000010203E3\nS1061000010203E4\n"), (2, "checksum mismatch"));
+        assert_eq!(srec_error("S1071000010203E3\n"), (1, "wrong byte count"));
+        assert_eq!(srec_error("S4030000FC\n"), (1, "unknown record type"));
+        assert_eq!(srec_error("X1061000010203E3\n"), (1, "record must start with 'S'"));
+        assert_eq!(srec_error("S106100001020\n"), (1, "odd number of hex digits"));
+        assert_eq!(srec_error("S10610000102G3E3\n"), (1, "wrong hex digit"));
+        assert_eq!(srec_error("S30210ED\n"), (1, "record too short"));
+    }
+
+    #[test]
+    fn unknown_extension_is_raw_binary() {
+        let path = std::env::temp_dir().join(format!("milcup-test-{}.img", std::process::id()));
+        fs::write(&path, b"S\x00\x01:").unwrap();
+        let image = read_firmware_file(&path, 0x08000000);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(segments(&image.unwrap()), vec![(0x08000000, 4)]);
+    }
+
+    /// Program header of test ELF file: type, vaddr, paddr, file data, memory size
+    type ProgramHeader<'a> = (u32, u64, u64, &'a [u8], u64);
+
+    /// Minimal little endian ELF file with program headers only
+    fn elf(class64: bool, entry: u64, headers: &[ProgramHeader]) -> Vec<u8> {
+        let word = |buf: &mut Vec<u8>, value: u64| match class64 {
+            true => buf.extend_from_slice(&value.to_le_bytes()),
+            false => buf.extend_from_slice(&(value as u32).to_le_bytes()),
+        };
+        let (ehsize, phentsize) = if class64 { (64u16, 56u16) } else { (52, 32) };
+
+        let mut buf = vec![0x7F, b'E', b'L', b'F', if class64 { 2 } else { 1 }, 1, 1];
+        buf.resize(16, 0);
+        buf.extend_from_slice(&2u16.to_le_bytes()); // executable
+        buf.extend_from_slice(&40u16.to_le_bytes()); // ARM
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        word(&mut buf, entry);
+        word(&mut buf, ehsize as u64); // program headers right after ELF header
+        word(&mut buf, 0); // no section headers
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        for value in [ehsize, phentsize, headers.len() as u16, 0, 0, 0] {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let mut offset = (ehsize + phentsize * headers.len() as u16) as u64;
+        for &(p_type, vaddr, paddr, data, memsz) in headers {
+            buf.extend_from_slice(&p_type.to_le_bytes());
+            if class64 {
+                buf.extend_from_slice(&0u32.to_le_bytes()); // flags
+            }
+            for value in [offset, vaddr, paddr, data.len() as u64, memsz] {
+                word(&mut buf, value);
+            }
+            if !class64 {
+                buf.extend_from_slice(&0u32.to_le_bytes()); // flags
+            }
+            word(&mut buf, 4); // align
+            offset += data.len() as u64;
+        }
+        for &(_, _, _, data, _) in headers {
+            buf.extend_from_slice(data);
+        }
+
+        return buf;
+    }
+
+    #[test]
+    fn elf_load_segments() {
+        let file = elf(false, 0x08000101, &[
+            (PT_LOAD, 0x08000000, 0x08000000, b"text", 4),
+            // .data runs in RAM and is loaded from flash
+            (PT_LOAD, 0x20000000, 0x08000100, b"data", 4),
+            // RAM code loaded by debugger
+            (PT_LOAD, 0x20000100, 0x20000100, b"ramf", 4),
+            // .bss
+            (PT_LOAD, 0x20000200, 0x20000200, b"", 0x100),
+            // note, not loaded at all
+            (4, 0x08000200, 0x08000200, b"note", 4),
+        ]);
+
+        let image = parse_elf_buffer(&file).unwrap();
+        assert_eq!(segments(&image), vec![(0x08000000, 4), (0x08000100, 4)]);
+        assert_eq!(image.segments[1].data, b"data");
+        assert_eq!(image.start, Some(0x08000101));
+    }
+
+    #[test]
+    fn elf_bad_segments() {
+        let file = elf(true, 0, &[(PT_LOAD, 0x1_0800_0000, 0x1_0800_0000, b"text", 4)]);
+        assert!(matches!(parse_elf_buffer(&file), Err(Error::Elf(_))));
+
+        // segment data out of file
+        let mut file = elf(false, 0, &[(PT_LOAD, 0x08000000, 0x08000000, b"text", 4)]);
+        file.truncate(file.len() - 2);
+        assert!(matches!(parse_elf_buffer(&file), Err(Error::Elf(_))));
+
+        // offset and size wrapping around
+        let mut file = elf(true, 0, &[(PT_LOAD, 0x08000000, 0x08000000, b"text", 4)]);
+        file[64 + 32..64 + 40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(parse_elf_buffer(&file), Err(Error::Elf(_))));
+    }
+}
@@ -0,0 +1,91 @@
+/// RAM boot loader images
+///
+/// Boot loader is uploaded to RAM with ROM boot loader commands and does
+/// the flash work. Built-in images are registered per chip here, custom one
+/// can be read from any firmware file.
+///
+use std::{
+    fmt,
+    path::Path,
+};
+
+use crate::{
+    firmware::{
+        self,
+        HexFile,
+    },
+    target::Chip,
+};
+
+/// Built-in boot loader image
+pub struct Loader {
+    pub name: &'static str,
+    /// Chips the loader works on
+    pub chips: &'static [&'static str],
+    /// Intel HEX image
+    pub hex: &'static str,
+}
+
+/// Built-in boot loaders
+pub const LOADERS: &[Loader] = &[
+    Loader {
+        name: "1986_BOOT_UART",
+        chips: &["1986VE91T", "1986VE92U", "1986VE93U", "1986VE94T", "K1986VE92QI"],
+        hex: include_str!("../firmware/1986_BOOT_UART.hex"),
+    },
+];
+
+#[derive(Debug)]
+pub enum Error {
+    Firmware(firmware::Error),
+    /// No built-in boot loader for the chip
+    NoLoader(&'static str),
+    /// Boot loader image does not fit into chip RAM
+    OutOfRam { addr: u32, end: u32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Firmware(ref err) => write!(f, "{}", err),
+            Error::NoLoader(chip) =>
+                write!(f, "There is no built-in boot loader for chip {}, use --loader", chip),
+            Error::OutOfRam { addr, end } =>
+                write!(f, "Boot loader 0x{:0>8X?}..0x{:0>8X?} is out of chip RAM", addr, end),
+        }
+    }
+}
+
+impl From<firmware::Error> for Error {
+    fn from(err: firmware::Error) -> Error {
+        Error::Firmware(err)
+    }
+}
+
+/// Built-in boot loader for the chip
+pub fn builtin(chip: &Chip) -> Option<&'static Loader> {
+    return LOADERS.iter().find(|loader| loader.chips.contains(&chip.name));
+}
+
+/// Read boot loader image
+///
+/// Custom loader file is used if given, built-in loader of the chip
+/// otherwise. Loader must lie in chip RAM as a whole, it is started from
+/// its first byte.
+///
+pub fn read_loader(path: Option<&Path>, chip: &Chip) -> Result<HexFile, Error> {
+    let image = match path {
+        Some(path) => firmware::read_firmware_file(path, chip.memory.ram().start)?,
+        None => {
+            let loader = builtin(chip).ok_or(Error::NoLoader(chip.name))?;
+            firmware::parse_hex_buffer(loader.hex)?
+        },
+    };
+
+    let ram = chip.memory.ram();
+    if !ram.contains(image.addr(), image.end().saturating_sub(image.addr())) {
+        return Err(Error::OutOfRam { addr: image.addr(), end: image.end() });
+    }
+
+    return Ok(image);
+}
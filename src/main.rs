@@ -1,26 +1,41 @@
+// explicit returns are used all over the code
+#![allow(clippy::needless_return)]
+
 use structopt::StructOpt;
 use serialport::prelude::*;
 use serialport::SerialPortType;
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-// use std::fmt;
-// use std::error::Error;
-// use std::io;
 
 use anyhow::{Context, Result, bail};
 
-use console::{style, Emoji};
-use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
-
-// static LOOKING_GLASS: Emoji<'_, '_> = Emoji("🚚 🔍 ", "🚚  ");
-// 🚚
+use console::style;
 
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 
+mod baud;
 mod firmware;
 mod command;
 mod com_port;
+mod emulator;
+mod target;
+mod loader;
+
+use baud::BaudRate;
+use com_port::Transport;
+
+/// Connection to the board
+type Port = Box<dyn Transport>;
+
+/// Port name to run against software emulator instead of real board
+const EMULATOR_PORT: &str = "emulator";
+
+/// Port name to run against software emulator with stock boot loader
+const EMULATOR_STOCK_PORT: &str = "emulator-stock";
 
 // Baud rate 
 // 9600,19200,57600,115200
@@ -28,18 +43,171 @@ mod com_port;
 #[derive(StructOpt)]
 #[structopt(about = "Milandr 1986 firmware uploader", rename_all = "kebab-case")]
 struct Cli {
-    #[structopt(default_value = "auto", short = "p", long = "port")]
+    #[structopt(default_value = "auto", short = "p", long = "port", global = true)]
     port_name: String,
-    #[structopt(default_value = "115200", short = "b", long = "baud")]
-    baud_rate: u32,
-    // #[structopt(default_value = true, short = "p", long = "program")]
-    // program: bool,
-    // #[structopt(default_value = true, short = "e", long = "erase")]
-    // erase: bool,
-    // #[structopt(default_value = true, short = "v", long = "verify")]
-    // verify: bool,
+    /// Baud rate or "auto" to find the fastest stable one
+    #[structopt(default_value = "115200", short = "b", long = "baud", global = true)]
+    baud_rate: BaudRate,
+    /// Board clock frequency in Hz UART baud rate is derived from
+    #[structopt(default_value = "8000000", long = "clock", global = true)]
+    clock: u32,
+    /// Number of attempts to resend failed firmware chunk
+    #[structopt(default_value = "3", long = "retries", global = true)]
+    retries: u32,
+    /// Target chip: 1986VE91T, 1986VE92U, 1986VE93U, 1986VE94T, K1986VE92QI, 1986VE1T or 1986VE3T
+    #[structopt(default_value = target::DEFAULT_CHIP, long = "chip", global = true, parse(try_from_str = target::find_chip))]
+    chip: &'static target::Chip,
+    /// RAM boot loader Intel HEX, S-record or raw binary file used instead of built-in one
+    #[structopt(long = "loader", global = true, parse(from_os_str))]
+    loader: Option<PathBuf>,
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+/// Firmware file to write or compare with
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct FirmwareFile {
+    /// Address raw binary (.bin) file is placed at, start of flash by default
+    #[structopt(long = "base-address", parse(try_from_str = parse_address))]
+    base_address: Option<u32>,
+    /// Intel HEX, S-record, ELF or raw binary file
     #[structopt(parse(from_os_str))]
-    path: std::path::PathBuf,
+    path: PathBuf,
+}
+
+/// Verify settings
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct VerifyOptions {
+    /// Report every wrong byte instead of stopping on the first one.
+    /// Pages with correct CRC are still not read back
+    #[structopt(long = "all-mismatches")]
+    all_mismatches: bool,
+}
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum Command {
+    /// Erase chip, write firmware and verify it
+    Flash {
+        #[structopt(flatten)]
+        file: FirmwareFile,
+        /// Value gaps between image segments and chunk bytes not covered by image are
+        /// filled with. With 0xFF gaps are not written and keep their content
+        #[structopt(default_value = "0xFF", long = "fill", parse(try_from_str = parse_byte))]
+        fill: u8,
+        /// Erase only flash pages the image is written to instead of whole chip.
+        /// Needs boot loader with page erase command
+        #[structopt(long = "page-erase")]
+        page_erase: bool,
+        /// Flash area to keep untouched as START..END or START+SIZE, implies --page-erase
+        #[structopt(long = "preserve", number_of_values = 1, parse(try_from_str = parse_range))]
+        preserve: Vec<Range<u32>>,
+        /// Compare image with flash and rewrite changed pages only.
+        /// Whole chip is erased if boot loader can't erase pages
+        #[structopt(long = "incremental")]
+        incremental: bool,
+        #[structopt(flatten)]
+        verify: VerifyOptions,
+        /// Start program after flashing, from image entry point if there is one
+        #[structopt(long = "run")]
+        run: bool,
+    },
+    /// Erase whole chip
+    Erase {
+        /// Check the whole flash is erased afterwards
+        #[structopt(long = "blank-check")]
+        blank_check: bool,
+    },
+    /// Check the whole flash is erased and report areas which are not
+    BlankCheck,
+    /// Compare flash content with firmware file
+    Verify {
+        #[structopt(flatten)]
+        file: FirmwareFile,
+        #[structopt(flatten)]
+        verify: VerifyOptions,
+    },
+    /// Read flash content to file.
+    /// File with .hex extension is saved as Intel HEX, otherwise raw binary
+    Dump {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Identify boot loader and chip
+    Info,
+    /// Start program from flash
+    Run {
+        /// Program entry point, fails if boot loader has no jump command
+        #[structopt(long = "address", parse(try_from_str = parse_address))]
+        address: Option<u32>,
+    },
+    /// List available COM ports
+    Ports,
+}
+
+impl Command {
+    /// Number of steps command takes apart from connection
+    fn steps(&self) -> usize {
+        match self {
+            Command::Flash { preserve, incremental, run, .. } =>
+                4 + if preserve.is_empty() { 0 } else { 1 } + if *incremental { 1 } else { 0 } + if *run { 1 } else { 0 },
+            Command::Verify { .. } => 2,
+            Command::Erase { blank_check: true } => 2,
+            Command::Ports => 0,
+            _ => 1,
+        }
+    }
+}
+
+/// Numbered step printer
+struct Steps {
+    step: usize,
+    total: usize,
+}
+
+impl Steps {
+    fn print(&mut self, str: &str) {
+        self.step += 1;
+        println!("{} {}", style(format!("[{}/{}]", self.step, self.total)).bold().dim(), str);
+    }
+}
+
+/// Parse address given as hex number with 0x prefix or decimal one
+fn parse_address(s: &str) -> Result<u32, String> {
+    let res = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse::<u32>(),
+    };
+
+    return res.map_err(|err| format!("Wrong address {}: {}", s, err));
+}
+
+/// Parse byte value given as hex number with 0x prefix or decimal one
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let value = parse_address(s)?;
+    return u8::try_from(value).map_err(|_| format!("Wrong byte value {}", s));
+}
+
+/// Parse memory range given as START..END or START+SIZE
+fn parse_range(s: &str) -> Result<Range<u32>, String> {
+    let (start, end) = if let Some((start, end)) = s.split_once("..") {
+        (parse_address(start)?, parse_address(end)?)
+    } else if let Some((start, size)) = s.split_once('+') {
+        let start = parse_address(start)?;
+        let end = start.checked_add(parse_address(size)?)
+            .ok_or_else(|| format!("Range {} is out of address space", s))?;
+        (start, end)
+    } else {
+        return Err(format!("Wrong range {}, use START..END or START+SIZE", s));
+    };
+
+    if start >= end {
+        return Err(format!("Range {} is empty", s));
+    }
+
+    return Ok(start..end);
 }
 
 /// Try to find available port automatically
@@ -92,6 +260,16 @@ impl std::error::Error for command::Error {
     }
 }
 
+impl std::error::Error for com_port::Error {
+    fn description(&self) -> &str {
+        "My custom error message"
+    }
+
+    fn cause(&self) -> Option<&dyn std::error::Error> {
+        None
+    }
+}
+
 impl std::error::Error for firmware::Error {
     fn description(&self) -> &str {
         "My custom error message"
@@ -102,112 +280,425 @@ impl std::error::Error for firmware::Error {
     }
 }
 
-// macro_rules! exec_step {
-//     ($step:tt, $expression:expr) => {
-//        $expression
-//     };
-// }
+impl std::error::Error for target::Error {
+    fn description(&self) -> &str {
+        "My custom error message"
+    }
+
+    fn cause(&self) -> Option<&dyn std::error::Error> {
+        None
+    }
+}
+
+impl std::error::Error for loader::Error {
+    fn description(&self) -> &str {
+        "My custom error message"
+    }
+
+    fn cause(&self) -> Option<&dyn std::error::Error> {
+        None
+    }
+}
 
 fn main() {
     if let Err(err) = try_main() {
-        // error!("{:#?}", err);
         // error!("{:#?}", err);
         // eprintln!("Error: {:?}", err);
-        // eprintln!("Error: {} [{}]", err.to_string(), err.root_cause());
-        error!("{} [{}]", err.to_string(), err.root_cause());
+        error!("{} [{}]", err, err.root_cause());
         std::process::exit(1);
     }
 }
 
-// fn try_main() -> Result<(), anyhow::Error> {
 fn try_main() -> Result<()> {
     env_logger::init();
 
-    let mut step = 0;
-    let mut print_step = |str: &str| {
-        step += 1;
-        println!("{} {}", style(format!("[{}/{}]", step, 10)).bold().dim(), str.to_string());
+    let args = Cli::from_args();
+
+    if let Command::Ports = args.cmd {
+        return list_ports();
+    }
+
+    let mut steps = Steps {
+        step: 0,
+        total: 5 + args.cmd.steps() - if args.port_name == "auto" { 0 } else { 1 },
     };
 
-    // warn!("[root] warn");
-    // info!("[root] info");
-    // debug!("[root] debug");
-    // error!("[root] error");
+    match &args.cmd {
+        Command::Flash { file, fill, page_erase, preserve, incremental, verify, run } => {
+            // firmware is checked before the chip is touched
+            steps.print("Read firmware");
+            let program_code = read_firmware(file, args.chip)?;
+
+            // filled gaps are written and erased as a part of the image
+            let program_code = if *fill == 0xFF { program_code } else { program_code.fill_gaps(*fill) };
+
+            let page_erase = *page_erase || !preserve.is_empty() || *incremental;
+            let mut pages = args.chip.pages(&program_code);
+            check_preserved(args.chip, &pages, preserve)?;
+
+            let mut port = connect(&args, &mut steps)?;
+
+            // only changed pages with old flash content around image data
+            // are written in incremental mode
+            let mut changed: Option<firmware::HexFile> = None;
+            if *incremental {
+                steps.print("Compare firmware with flash");
+                let blocks = command::changed_pages(&mut port, &program_code, args.chip.page_size)
+                    .with_context(|| "Compare firmware with flash")?;
+
+                let mut image = firmware::HexFile::new();
+                for block in &blocks {
+                    image.insert(block.addr, &block.data)?;
+                }
+                pages = blocks.iter().map(|block| block.addr).collect();
+                println!("    Changed pages: {}", pages.len());
+                changed = Some(image);
+            }
+
+            // Erase
+            let preserved = read_preserved(&mut port, preserve)?;
+            if page_erase {
+                steps.print(format!("Erase {} flash pages", pages.len()).as_str());
+                match command::erase_pages(&mut port, &pages) {
+                    // nothing is erased yet, it is safe to start over
+                    Err(command::Error::Unsupported(_)) if *incremental && preserve.is_empty() => {
+                        println!("Warning: boot loader can't erase pages, erasing whole chip");
+                        command::erase(&mut port, args.chip)
+                            .with_context(|| "Erase chip")?;
+                        changed = None;
+                    },
+                    res => res.with_context(|| "Erase flash pages")?,
+                }
+            } else {
+                steps.print("Erase chip");
+                command::erase(&mut port, args.chip)
+                    .with_context(|| "Erase chip")?;
+            }
+
+            // Program
+            steps.print("Writing firmware");
+            command::program(&mut port, changed.as_ref().unwrap_or(&program_code), *fill, args.retries, true)
+                .with_context(|| "Flash program firmware")?;
+
+            // Verify
+            steps.print("Verify");
+            verify_flash(&mut port, &program_code, args.chip, verify)?;
+
+            if !preserve.is_empty() {
+                steps.print("Check preserved areas");
+                check_unchanged(&mut port, preserve, &preserved)?;
+            }
+
+            if *run {
+                steps.print("Run program");
+                run_program(&mut port, program_code.start)?;
+            }
+        },
+        Command::Erase { blank_check: check } => {
+            let mut port = connect(&args, &mut steps)?;
+
+            steps.print("Erase chip");
+            command::erase(&mut port, args.chip)
+                .with_context(|| "Erase chip")?;
+
+            if *check {
+                steps.print("Blank check");
+                blank_check(&mut port, args.chip)?;
+            }
+        },
+        Command::BlankCheck => {
+            let mut port = connect(&args, &mut steps)?;
+
+            steps.print("Blank check");
+            blank_check(&mut port, args.chip)?;
+        },
+        Command::Verify { file, verify } => {
+            steps.print("Read firmware");
+            let program_code = read_firmware(file, args.chip)?;
+
+            let mut port = connect(&args, &mut steps)?;
+
+            steps.print("Verify");
+            verify_flash(&mut port, &program_code, args.chip, verify)?;
+        },
+        Command::Dump { path } => {
+            let mut port = connect(&args, &mut steps)?;
+
+            steps.print("Reading firmware");
+            dump(&mut port, path, args.chip)?;
+        },
+        Command::Info => {
+            // boot loader and core are identified on connect
+            connect(&args, &mut steps)?;
+
+            steps.print("Chip info");
+            let flash = args.chip.memory.flash();
+            println!("    Chip: {} flash 0x{:0>8X?} {} KB, page {} bytes",
+                args.chip.name, flash.start, flash.size / 1024, args.chip.page_size);
+        },
+        Command::Run { address } => {
+            let mut port = connect(&args, &mut steps)?;
+
+            steps.print("Run program");
+            match address {
+                // explicit address must be honoured, there is no fallback
+                Some(addr) => command::run_at(&mut port, *addr)
+                    .with_context(|| format!("Run program at 0x{:0>8X?}", addr))?,
+                None => command::run(&mut port)
+                    .with_context(|| "Run program")?,
+            }
+        },
+        Command::Ports => unreachable!(),
+    }
 
-    let args = Cli::from_args();
+    return Ok(());
+}
+
+/// Open COM port and start boot loader in board RAM
+///
+/// Connection is set up at 9600 baud, then switched to requested baud rate
+/// and custom boot loader is uploaded, so the port is ready to accept flash
+/// commands
+///
+fn connect(args: &Cli, steps: &mut Steps) -> Result<Port> {
+    // boot loader image is checked before the board is touched as well
+    let boot_loader = loader::read_loader(args.loader.as_deref(), args.chip)
+        .with_context(|| "Read boot loader code")?;
+
+    // refuse baud rate board can't work on before touching it
+    if let BaudRate::Fixed(baud_rate) = args.baud_rate {
+        let warning = baud::check(args.clock, baud_rate)
+            .map_err(anyhow::Error::msg)
+            .with_context(|| "Check baud rate")?;
+        if let Some(warning) = warning {
+            println!("Warning: {}", warning);
+        }
+    }
+
+    let settings = SerialPortSettings {
+        timeout: Duration::from_millis(3000),
+        baud_rate: 9600, // initial baud rate
+        ..Default::default()
+    };
 
-    let mut settings: SerialPortSettings = Default::default();
-    settings.timeout = Duration::from_millis(3000);
-    settings.baud_rate = 9600; // initial baud rate
-    
     let port_name = if args.port_name == "auto" {
-        print_step("Probe COM port...");
-        probe_port().with_context(|| format!("Probe com port"))?
+        steps.print("Probe COM port...");
+        probe_port().with_context(|| "Probe com port")?
     } else {
-        args.port_name
+        args.port_name.clone()
     };
 
-    print_step(format!("Using COM port {}", port_name).as_str());
-    let mut port = serialport::open_with_settings(&port_name, &settings)
-        .with_context(|| format!("Open COM port with default baud rate 9600"))?;
+    steps.print(format!("Using COM port {}", port_name).as_str());
+    let mut port: Port = if port_name == EMULATOR_PORT || port_name == EMULATOR_STOCK_PORT {
+        Box::new(emulator::spawn(args.chip, port_name == EMULATOR_PORT))
+    } else {
+        Box::new(serialport::open_with_settings(&port_name, &settings)
+            .with_context(|| "Open COM port with default baud rate 9600")?)
+    };
 
-    // println!("Checking port");
     command::check_port(&mut port)
-        .with_context(|| format!("Check COM port availability"))?;
+        .with_context(|| "Check COM port availability")?;
+
+    match args.baud_rate {
+        BaudRate::Auto => {
+            steps.print("Negotiate baud rate");
+            let rates = baud::BAUD_RATES.iter()
+                .filter(|&&rate| baud::check(args.clock, rate).is_ok())
+                .cloned()
+                .collect::<Vec<u32>>();
+
+            let baud_rate = command::negotiate_baud_rate(&mut port, &rates)
+                .with_context(|| "Negotiate baud rate")?;
+            println!("    Baud rate: {}", baud_rate);
+        },
+        BaudRate::Fixed(baud_rate) => {
+            steps.print(format!("Set baud rate {}", baud_rate).as_str());
+            // board can't be brought back if the port fails to follow it
+            command::check_port_rate(&mut port, baud_rate)
+                .with_context(|| format!("Port can't work at baud rate {}", baud_rate))?;
+
+            command::set_baud_rate(&mut port, baud_rate)
+                .with_context(|| "Set baud rate")?;
+
+            // switch port to the new baud rate
+            port.set_baud_rate(baud_rate)
+                .with_context(|| "Switch port to new baud rate")?;
+
+            command::read_baud_rate(&mut port)
+                .with_context(|| "Read baud rate settings")?;
+        },
+    }
 
-    print_step(format!("Set baud rate {}", args.baud_rate).as_str());
-    command::set_baud_rate(&mut port, args.baud_rate)
-        .with_context(|| format!("Set baud rate"))?;
-        // .with_context(|| format!("Set baud rate {}", args.baud_rate))?;
+    steps.print("Writing boot loader");
+    match (&args.loader, loader::builtin(args.chip)) {
+        (Some(path), _) => println!("    Loader: {}", path.display()),
+        (None, Some(builtin)) => println!("    Loader: built-in {}", builtin.name),
+        (None, None) => {},
+    }
+    command::boot_load(&mut port, boot_loader)
+        .with_context(|| "Load boot loader code to board RAM")?;
+
+    // make sure the right chip is connected before anything is written to flash
+    steps.print("Identify chip");
+    let info = command::read_info(&mut port)
+        .with_context(|| "Read boot loader identifier string")?;
+    println!("    Boot loader: {}", info);
+
+    match command::read_cpuid(&mut port) {
+        Ok(cpuid) => {
+            println!("    Core: {} (CPUID 0x{:0>8X?})", target::core_name(cpuid), cpuid);
+            args.chip.check_cpuid(cpuid)
+                .with_context(|| "Check connected chip")?;
+        },
+        Err(command::Error::Unsupported(_)) =>
+            println!("    Core: not checked, boot loader can't read CPUID"),
+        Err(err) => return Err(err).with_context(|| "Read CPUID"),
+    }
+
+    return Ok(port);
+}
 
-    std::mem::drop(port); // close port 
+/// Print all the COM ports found in the system
+fn list_ports() -> Result<()> {
+    let ports = serialport::available_ports()?;
+    if ports.is_empty() {
+        println!("No COM ports found");
+    }
 
-    settings.baud_rate = args.baud_rate; // set new baud rate
+    for port in ports {
+        match port.port_type {
+            SerialPortType::UsbPort(info) => println!("{} USB VID:{:04x} PID:{:04x} {}",
+                port.port_name, info.vid, info.pid,
+                info.product.as_ref().map_or("", String::as_str)),
+            SerialPortType::PciPort => println!("{} PCI", port.port_name),
+            SerialPortType::BluetoothPort => println!("{} Bluetooth", port.port_name),
+            SerialPortType::Unknown => println!("{}", port.port_name),
+        }
+    }
 
-    // and reopen with new baud rate
-    let mut port = serialport::open_with_settings(&port_name, &settings)
-        .with_context(|| format!("Reopen port with new baud rate"))?;
-        // .with_context(|| format!("Reopen port with new baud rate {}", args.baud_rate))?;
-    // println!("Open port with baud rate {}", settings.baud_rate);
+    return Ok(());
+}
 
-    // println!("Read baud rate");
-    command::read_baud_rate(&mut port)
-        .with_context(|| format!("Read baud rate settings"))?;
+/// Read, print and check firmware file
+fn read_firmware(file: &FirmwareFile, chip: &target::Chip) -> Result<firmware::HexFile> {
+    let base_address = file.base_address.unwrap_or(chip.memory.flash().start);
+    let program_code = firmware::read_firmware_file(&file.path, base_address)
+        .with_context(|| format!("Read firmware program code {}", file.path.display()))?;
 
-    print_step("Writing boot loader");
-    let boot_loader = include_str!("../firmware/1986_BOOT_UART.hex");
-    let hex_file = firmware::parse_hex_buffer(boot_loader)
-        .with_context(|| format!("Parse boot loader code"))?;
+    program_code.print_summary();
 
-    // println!("Hex buffer length {}", hex_file.buf.len());
+    chip.memory.validate(&program_code)
+        .with_context(|| "Check firmware fits into flash")?;
 
-    command::boot_load(&mut port, hex_file)
-        .with_context(|| format!("Load boot loader code to board RAM"))?;
-    // println!("ok");
+    return Ok(program_code);
+}
 
-    // println!("Read board info");
-    command::read_info(&mut port)
-        .with_context(|| format!("Read boot loader identifier string"))?;
+/// Make sure preserved areas are in flash and are not erased with image pages
+fn check_preserved(chip: &target::Chip, pages: &[u32], preserve: &[Range<u32>]) -> Result<()> {
+    let flash = chip.memory.flash();
 
-    // Erase
-    print_step("Erase chip");
-    command::erase(&mut port)
-        .with_context(|| format!("Erase chip"))?;
+    for range in preserve {
+        if !flash.contains(range.start, range.end - range.start) {
+            bail!("Preserved area 0x{:0>8X?}..0x{:0>8X?} is out of flash", range.start, range.end);
+        }
 
-    // Program
-    print_step("Writing firmware");
-    let program_code  = firmware::read_hex_file(std::path::Path::new(&args.path))
-        .with_context(|| format!("Read firmware program code"))?;
+        let page = pages.iter().find(|&&page| range.start < page + chip.page_size && page < range.end);
+        if let Some(page) = page {
+            bail!("Preserved area 0x{:0>8X?}..0x{:0>8X?} overlaps flash page 0x{:0>8X?} the image is written to",
+                range.start, range.end, page);
+        }
+    }
 
-    // println!("Hex buffer length {}", program_code.buf.len());
+    return Ok(());
+}
 
-    command::program(&mut port, &program_code)
-        .with_context(|| format!("Flash program firmware"))?;
+/// Read content of preserved areas
+fn read_preserved(port: &mut Port, preserve: &[Range<u32>]) -> Result<Vec<Vec<u8>>> {
+    return preserve.iter()
+        .map(|range| command::read_flash(port, range.start, range.end - range.start)
+            .with_context(|| format!("Read preserved area 0x{:0>8X?}..0x{:0>8X?}", range.start, range.end)))
+        .collect();
+}
+
+/// Compare preserved areas with their content read before erase
+fn check_unchanged(port: &mut Port, preserve: &[Range<u32>], before: &[Vec<u8>]) -> Result<()> {
+    let after = read_preserved(port, preserve)?;
+
+    for ((range, before), after) in preserve.iter().zip(before).zip(after) {
+        if let Some(pos) = before.iter().zip(&after).position(|(a, b)| a != b) {
+            bail!("Preserved area 0x{:0>8X?}..0x{:0>8X?} changed at 0x{:0>8X?}",
+                range.start, range.end, range.start + pos as u32);
+        }
+    }
+
+    return Ok(());
+}
+
+/// Start program at image entry point
+///
+/// Program is started the way stock boot loader does if boot loader can't
+/// jump to the entry point
+///
+fn run_program(port: &mut Port, addr: Option<u32>) -> Result<()> {
+    if let Some(addr) = addr {
+        match command::run_at(port, addr) {
+            Err(command::Error::Unsupported(_)) =>
+                println!("Warning: boot loader can't jump to 0x{:0>8X?}, starting program from flash", addr),
+            res => return res.with_context(|| format!("Run program at 0x{:0>8X?}", addr)),
+        }
+    }
+
+    return command::run(port)
+        .with_context(|| "Run program");
+}
+
+/// Compare flash with the image, print all the mismatches if requested
+fn verify_flash(port: &mut Port, image: &firmware::HexFile, chip: &target::Chip, options: &VerifyOptions) -> Result<()> {
+    let res = command::verify_crc(port, image, chip.page_size, options.all_mismatches);
+    if let Err(command::Error::Mismatches(ref mismatches)) = res {
+        for mismatch in mismatches {
+            println!("    {}", mismatch);
+        }
+    }
+
+    return res.with_context(|| "Verify written data");
+}
+
+/// Check the whole flash is erased, print areas which are not
+fn blank_check(port: &mut Port, chip: &target::Chip) -> Result<()> {
+    let flash = chip.memory.flash();
+    let ranges = command::blank_check(port, flash.start, flash.size)
+        .with_context(|| "Read flash memory")?;
+
+    for range in &ranges {
+        println!("    Not blank: 0x{:0>8X?}..0x{:0>8X?} {} bytes", range.start, range.end, range.end - range.start);
+    }
+
+    if !ranges.is_empty() {
+        bail!("Flash is not blank, {} areas found", ranges.len());
+    }
+
+    println!("    Flash is blank");
+    return Ok(());
+}
+
+/// Save whole flash content to file
+fn dump(port: &mut Port, path: &Path, chip: &target::Chip) -> Result<()> {
+    let flash = chip.memory.flash();
+    let buf = command::read_flash(port, flash.start, flash.size)
+        .with_context(|| "Read flash memory")?;
+
+    let mut image = firmware::HexFile::new();
+    image.insert(flash.start, &buf)?;
+
+    let is_hex = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("hex"));
+    if is_hex {
+        firmware::write_hex_file(path, &image)
+    } else {
+        firmware::write_bin_file(path, &image)
+    }.with_context(|| format!("Write flash content to {}", path.display()))?;
 
-    // Verify
-    print_step("Verify");
-    command::verify(&mut port, &program_code)
-        .with_context(|| format!("Verify written data"))?;
+    println!("    Saved {} bytes to {}", buf.len(), path.display());
 
     return Ok(());
 }
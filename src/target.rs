@@ -0,0 +1,251 @@
+/// Target chip profiles and memory map
+///
+use std::fmt;
+
+use crate::firmware::HexFile;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RegionKind {
+    Flash,
+    Ram,
+}
+
+/// Continuous memory area of the chip
+pub struct Region {
+    pub name: &'static str,
+    pub kind: RegionKind,
+    pub start: u32,
+    pub size: u32,
+}
+
+impl Region {
+    /// Address right after the last byte of region
+    pub fn end(&self) -> u32 {
+        return self.start + self.size;
+    }
+
+    /// Check if `len` bytes starting from `addr` fit into region
+    pub fn contains(&self, addr: u32, len: u32) -> bool {
+        return addr >= self.start && addr as u64 + len as u64 <= self.end() as u64;
+    }
+}
+
+pub struct MemoryMap {
+    pub regions: &'static [Region],
+}
+
+/// Memory map of 1986VE9x chips
+pub const MDR1986VE9X: MemoryMap = MemoryMap {
+    regions: &[
+        Region { name: "Flash", kind: RegionKind::Flash, start: 0x08000000, size: 0x20000 },
+        Region { name: "RAM", kind: RegionKind::Ram, start: 0x20000000, size: 0x8000 },
+    ],
+};
+
+/// Memory map of 1986VE1T and 1986VE3T chips
+pub const MDR1986VE1T: MemoryMap = MemoryMap {
+    regions: &[
+        Region { name: "Flash", kind: RegionKind::Flash, start: 0x00000000, size: 0x20000 },
+        Region { name: "RAM", kind: RegionKind::Ram, start: 0x20000000, size: 0xC000 },
+    ],
+};
+
+/// CPUID bits identifying the core: implementer, architecture and part number.
+/// Variant and revision differ between chip revisions and are not checked
+pub const CPUID_MASK: u32 = 0xFF0FFFF0;
+
+/// ARM Cortex-M3 core of 1986VE9x chips
+pub const CORTEX_M3: u32 = 0x410FC230;
+
+/// ARM Cortex-M1 core of 1986VE1T and 1986VE3T chips
+pub const CORTEX_M1: u32 = 0x410CC210;
+
+/// Target chip profile
+pub struct Chip {
+    pub name: &'static str,
+    pub memory: MemoryMap,
+    /// Expected CPUID value masked with `CPUID_MASK`
+    pub cpuid: u32,
+    /// Flash erase page size
+    pub page_size: u32,
+}
+
+/// Chip used when none is given
+pub const DEFAULT_CHIP: &str = "1986VE92U";
+
+/// Supported chips
+pub const CHIPS: &[Chip] = &[
+    Chip { name: "1986VE91T", memory: MDR1986VE9X, cpuid: CORTEX_M3, page_size: 0x1000 },
+    Chip { name: "1986VE92U", memory: MDR1986VE9X, cpuid: CORTEX_M3, page_size: 0x1000 },
+    Chip { name: "1986VE93U", memory: MDR1986VE9X, cpuid: CORTEX_M3, page_size: 0x1000 },
+    Chip { name: "1986VE94T", memory: MDR1986VE9X, cpuid: CORTEX_M3, page_size: 0x1000 },
+    Chip { name: "K1986VE92QI", memory: MDR1986VE9X, cpuid: CORTEX_M3, page_size: 0x1000 },
+    Chip { name: "1986VE1T", memory: MDR1986VE1T, cpuid: CORTEX_M1, page_size: 0x1000 },
+    Chip { name: "1986VE3T", memory: MDR1986VE1T, cpuid: CORTEX_M1, page_size: 0x1000 },
+];
+
+/// Find chip profile by name, case insensitive
+pub fn find_chip(name: &str) -> Result<&'static Chip, String> {
+    return CHIPS.iter()
+        .find(|chip| chip.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("Unknown chip {}, supported ones are: {}", name,
+            CHIPS.iter().map(|chip| chip.name).collect::<Vec<&str>>().join(", ")));
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Data does not fit into flash, `region` is where it starts if any
+    OutOfRange { addr: u32, end: u32, region: Option<&'static str> },
+    Oversize { size: u32, capacity: u32 },
+    Empty,
+    /// Connected chip core does not match the selected chip
+    WrongChip { chip: &'static str, cpuid: u32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::OutOfRange { addr, end, region: Some(region) } =>
+                write!(f, "Data 0x{:0>8X?}..0x{:0>8X?} is out of flash, it starts in {}", addr, end, region),
+            Error::OutOfRange { addr, end, region: None } =>
+                write!(f, "Data 0x{:0>8X?}..0x{:0>8X?} is out of chip memory", addr, end),
+            Error::Oversize { size, capacity } =>
+                write!(f, "Image size {} bytes exceeds flash size {} bytes", size, capacity),
+            Error::Empty => write!(f, "Image has no data"),
+            Error::WrongChip { chip, cpuid } =>
+                write!(f, "Connected chip with {} (CPUID 0x{:0>8X?}) is not {}", core_name(cpuid), cpuid, chip),
+        }
+    }
+}
+
+impl Chip {
+    /// Start addresses of flash pages image data lies in
+    pub fn pages(&self, image: &HexFile) -> Vec<u32> {
+        let mut pages: Vec<u32> = vec![];
+        for seg in &image.segments {
+            let mut page = seg.addr - seg.addr % self.page_size;
+            while page < seg.end() {
+                if pages.last() != Some(&page) {
+                    pages.push(page);
+                }
+                page += self.page_size;
+            }
+        }
+
+        return pages;
+    }
+
+    /// Check that CPUID read from the board belongs to this chip
+    ///
+    /// Only the core family is checked: it tells 1986VE9x chips from
+    /// 1986VE1T/3T ones, but all 1986VE9x parts have the same core and
+    /// memory map and are not told from each other, so choosing another
+    /// 1986VE9x part is not detected
+    ///
+    pub fn check_cpuid(&self, cpuid: u32) -> Result<(), Error> {
+        if cpuid & CPUID_MASK != self.cpuid {
+            return Err(Error::WrongChip { chip: self.name, cpuid });
+        }
+
+        return Ok(());
+    }
+}
+
+/// Human readable core name with revision like "Cortex-M3 r2p1"
+pub fn core_name(cpuid: u32) -> String {
+    let core = match cpuid & CPUID_MASK {
+        CORTEX_M3 => "Cortex-M3",
+        CORTEX_M1 => "Cortex-M1",
+        _ => return "unknown core".to_string(),
+    };
+
+    return format!("{} r{}p{}", core, (cpuid >> 20) & 0xF, cpuid & 0xF);
+}
+
+impl MemoryMap {
+    /// Main flash region
+    pub fn flash(&self) -> &Region {
+        return self.regions.iter().find(|region| region.kind == RegionKind::Flash).unwrap();
+    }
+
+    /// Main RAM region
+    pub fn ram(&self) -> &Region {
+        return self.regions.iter().find(|region| region.kind == RegionKind::Ram).unwrap();
+    }
+
+    /// Region containing given address
+    pub fn region(&self, addr: u32) -> Option<&Region> {
+        return self.regions.iter().find(|region| region.contains(addr, 1));
+    }
+
+    /// Check that the image can be written to flash
+    ///
+    /// Every byte of image must lie inside one of flash regions
+    ///
+    pub fn validate(&self, image: &HexFile) -> Result<(), Error> {
+        if image.segments.is_empty() {
+            return Err(Error::Empty);
+        }
+
+        let capacity = self.regions.iter()
+            .filter(|region| region.kind == RegionKind::Flash)
+            .map(|region| region.size)
+            .sum();
+        if image.size() > capacity {
+            return Err(Error::Oversize { size: image.size(), capacity });
+        }
+
+        for seg in &image.segments {
+            let len = seg.data.len() as u32;
+            let in_flash = self.regions.iter()
+                .any(|region| region.kind == RegionKind::Flash && region.contains(seg.addr, len));
+
+            if !in_flash {
+                return Err(Error::OutOfRange {
+                    addr: seg.addr,
+                    end: seg.end(),
+                    region: self.region(seg.addr).map(|region| region.name),
+                });
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(segments: &[(u32, usize)]) -> HexFile {
+        let mut image = HexFile::new();
+        for &(addr, len) in segments {
+            image.insert(addr, &vec![0; len]).unwrap();
+        }
+        return image;
+    }
+
+    #[test]
+    fn validate_accepts_whole_flash() {
+        assert!(MDR1986VE9X.validate(&image(&[(0x08000000, 0x100), (0x0801FF00, 0x100)])).is_ok());
+        assert!(MDR1986VE9X.validate(&image(&[(0x08000000, 0x20000)])).is_ok());
+    }
+
+    #[test]
+    fn validate_errors() {
+        assert!(matches!(MDR1986VE9X.validate(&HexFile::new()), Err(Error::Empty)));
+
+        assert!(matches!(MDR1986VE9X.validate(&image(&[(0x08000000, 0x20001)])),
+            Err(Error::Oversize { size: 0x20001, capacity: 0x20000 })));
+
+        assert!(matches!(MDR1986VE9X.validate(&image(&[(0x20000000, 0x10)])),
+            Err(Error::OutOfRange { addr: 0x20000000, end: 0x20000010, region: Some("RAM") })));
+
+        // crosses the end of flash
+        assert!(matches!(MDR1986VE9X.validate(&image(&[(0x0801FFF0, 0x20)])),
+            Err(Error::OutOfRange { addr: 0x0801FFF0, end: 0x08020010, region: Some("Flash") })));
+
+        assert!(matches!(MDR1986VE9X.validate(&image(&[(0x10000000, 0x10)])),
+            Err(Error::OutOfRange { region: None, .. })));
+    }
+}